@@ -1,5 +1,8 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{transfer, Mint, Token, TokenAccount, Transfer};
+use anchor_lang::system_program;
+use anchor_spl::token_interface::{
+    close_account, transfer_checked, CloseAccount, Mint, TokenAccount, TokenInterface, TransferChecked,
+};
 use anchor_spl::associated_token::AssociatedToken;
 
 declare_id!("FVViRGPShMjCeSF3LDrp2qDjp6anRz9WAMiJrsGCRUzN");
@@ -10,8 +13,25 @@ pub const MEMBER_SEED: &[u8] = b"member";
 pub const VAULT_SEED: &[u8] = b"vault";
 pub const VAULT_AUTH_SEED: &[u8] = b"vault_auth";
 pub const STAKE_SEED: &[u8] = b"stake";
-pub const MAX_METADATA_SIZE: usize = 512;
-pub const MAX_MEMBERS: u16 = 100;
+pub const INVITE_SEED: &[u8] = b"invite";
+pub const BAN_SEED: &[u8] = b"ban";
+pub const DM_SEED: &[u8] = b"dm";
+pub const USER_KEY_SEED: &[u8] = b"user_key";
+pub const KEY_ENVELOPE_SEED: &[u8] = b"key_envelope";
+pub const MESSAGE_SEED: &[u8] = b"message";
+pub const PINS_SEED: &[u8] = b"pins";
+pub const MAX_METADATA_SIZE: usize = 1024;
+pub const DEFAULT_MAX_MEMBERS: u16 = 100;
+pub const MAX_MEMBERS: u16 = 10_000;
+pub const DM_MEMBERS: u16 = 2;
+pub const MAX_WRAPPED_KEY_SIZE: usize = 128;
+pub const MAX_BATCH_MESSAGES: usize = 10;
+pub const MAX_REACTION_SIZE: usize = 64;
+pub const MAX_PINNED_MESSAGES: usize = 10;
+pub const MAX_PENALTY_BPS: u16 = 10_000;
+pub const REWARD_PRECISION: u128 = 1_000_000_000_000;
+pub const METADATA_SEED: &[u8] = b"metadata";
+pub const TOKEN_METADATA_PROGRAM_ID: Pubkey = pubkey!("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s");
 
 // ==================== PROGRAM ====================
 #[program]
@@ -25,13 +45,24 @@ pub mod shield_chat {
         channel_id: u64,
         encrypted_metadata: Vec<u8>,
         channel_type: ChannelType,
+        max_members: Option<u16>,
     ) -> Result<()> {
         require!(
             encrypted_metadata.len() <= MAX_METADATA_SIZE,
             ErrorCode::MetadataTooLarge
         );
+        let max_members = max_members.unwrap_or(DEFAULT_MAX_MEMBERS);
+        require!(
+            (1..=MAX_MEMBERS).contains(&max_members),
+            ErrorCode::InvalidMemberLimit
+        );
+        require!(
+            channel_type != ChannelType::DirectMessage,
+            ErrorCode::UseCreateDirectMessage
+        );
 
         let channel = &mut ctx.accounts.channel;
+        let member = &mut ctx.accounts.member;
         let clock = Clock::get()?;
 
         channel.channel_id = channel_id;
@@ -42,8 +73,32 @@ pub mod shield_chat {
         channel.message_count = 0;
         channel.created_at = clock.unix_timestamp;
         channel.is_active = true;
+        channel.pending_owner = None;
+        channel.max_members = max_members;
+        channel.key_epoch = 0;
+        channel.message_digest = [0; 32];
+        channel.slow_mode_seconds = 0;
+        channel.posting_policy = PostingPolicy::Everyone;
+        channel.retention_seconds = 0;
+        channel.expired_through = 0;
+        channel.retention_mark = 0;
+        channel.retention_mark_at = 0;
+        channel.required_collection = None;
+        channel.gate_mode = GateMode::Stake;
+        channel.min_lock_seconds = 0;
+        channel.early_exit_penalty_bps = 0;
+        channel.treasury = None;
         channel.bump = ctx.bumps.channel;
 
+        // The owner's seat is backed by a real Member account so it can be vacated
+        member.channel = channel.key();
+        member.wallet = ctx.accounts.owner.key();
+        member.joined_at = clock.unix_timestamp;
+        member.is_active = true;
+        member.role = MemberRole::Owner;
+        member.key_version = UserKey::version_of(ctx.accounts.user_key.as_deref());
+        member.bump = ctx.bumps.member;
+
         msg!("Channel created: ID {}", channel_id);
         msg!("Owner: {}", channel.owner);
         msg!("Type: {:?}", channel.channel_type);
@@ -59,11 +114,21 @@ pub mod shield_chat {
         channel_id: u64,
         encrypted_metadata: Vec<u8>,
         channel_type: ChannelType,
+        max_members: Option<u16>,
     ) -> Result<()> {
         require!(
             encrypted_metadata.len() <= MAX_METADATA_SIZE,
             ErrorCode::MetadataTooLarge
         );
+        let max_members = max_members.unwrap_or(DEFAULT_MAX_MEMBERS);
+        require!(
+            (1..=MAX_MEMBERS).contains(&max_members),
+            ErrorCode::InvalidMemberLimit
+        );
+        require!(
+            channel_type != ChannelType::DirectMessage,
+            ErrorCode::UseCreateDirectMessage
+        );
 
         let clock = Clock::get()?;
         let channel = &mut ctx.accounts.channel;
@@ -80,6 +145,21 @@ pub mod shield_chat {
         channel.is_active = true;
         channel.required_token_mint = None;
         channel.min_token_amount = None;
+        channel.pending_owner = None;
+        channel.max_members = max_members;
+        channel.key_epoch = 0;
+        channel.message_digest = [0; 32];
+        channel.slow_mode_seconds = 0;
+        channel.posting_policy = PostingPolicy::Everyone;
+        channel.retention_seconds = 0;
+        channel.expired_through = 0;
+        channel.retention_mark = 0;
+        channel.retention_mark_at = 0;
+        channel.required_collection = None;
+        channel.gate_mode = GateMode::Stake;
+        channel.min_lock_seconds = 0;
+        channel.early_exit_penalty_bps = 0;
+        channel.treasury = None;
         channel.bump = ctx.bumps.channel;
 
         // Initialize member (creator auto-joins)
@@ -87,6 +167,8 @@ pub mod shield_chat {
         member.wallet = ctx.accounts.creator.key();
         member.joined_at = clock.unix_timestamp;
         member.is_active = true;
+        member.role = MemberRole::Owner;
+        member.key_version = UserKey::version_of(ctx.accounts.user_key.as_deref());
         member.bump = ctx.bumps.member;
 
         msg!("Channel created and joined: ID {}", channel_id);
@@ -96,109 +178,177 @@ pub mod shield_chat {
         Ok(())
     }
 
+    /// Create a two-party direct message channel with both members joined
+    /// The channel PDA is derived from the sorted wallet pair, so each pair has
+    /// exactly one DM; nobody else can ever join it.
+    /// The peer does not sign: either side can leave, and once both have left
+    /// the channel can be closed and opened again by either wallet. Member
+    /// records left over from an earlier incarnation are reactivated rather
+    /// than requiring both sides to call close_member first
+    pub fn create_direct_message(
+        ctx: Context<CreateDirectMessage>,
+        encrypted_metadata: Vec<u8>,
+    ) -> Result<()> {
+        require!(
+            encrypted_metadata.len() <= MAX_METADATA_SIZE,
+            ErrorCode::MetadataTooLarge
+        );
+        require_keys_neq!(
+            ctx.accounts.creator.key(),
+            ctx.accounts.peer.key(),
+            ErrorCode::InvalidDirectMessagePeer
+        );
+
+        let clock = Clock::get()?;
+        let channel = &mut ctx.accounts.channel;
+
+        // Initialize channel
+        channel.channel_id = 0;
+        channel.owner = ctx.accounts.creator.key();
+        channel.encrypted_metadata = encrypted_metadata;
+        channel.channel_type = ChannelType::DirectMessage;
+        channel.member_count = DM_MEMBERS;
+        channel.message_count = 0;
+        channel.created_at = clock.unix_timestamp;
+        channel.is_active = true;
+        channel.required_token_mint = None;
+        channel.min_token_amount = None;
+        channel.pending_owner = None;
+        channel.max_members = DM_MEMBERS;
+        channel.key_epoch = 0;
+        channel.message_digest = [0; 32];
+        channel.slow_mode_seconds = 0;
+        channel.posting_policy = PostingPolicy::Everyone;
+        channel.retention_seconds = 0;
+        channel.expired_through = 0;
+        channel.retention_mark = 0;
+        channel.retention_mark_at = 0;
+        channel.required_collection = None;
+        channel.gate_mode = GateMode::Stake;
+        channel.min_lock_seconds = 0;
+        channel.early_exit_penalty_bps = 0;
+        channel.treasury = None;
+        channel.bump = ctx.bumps.channel;
+
+        // Both participants can manage the conversation, and either one can
+        // take over ownership if the other leaves
+        let creator_member = &mut ctx.accounts.creator_member;
+        creator_member.channel = channel.key();
+        creator_member.wallet = ctx.accounts.creator.key();
+        creator_member.joined_at = clock.unix_timestamp;
+        creator_member.is_active = true;
+        creator_member.role = MemberRole::Owner;
+        creator_member.key_version = UserKey::version_of(ctx.accounts.creator_key.as_deref());
+        creator_member.last_message_at = 0;
+        creator_member.can_post = false;
+        creator_member.gate_token_account = Pubkey::default();
+        creator_member.bump = ctx.bumps.creator_member;
+
+        let peer_member = &mut ctx.accounts.peer_member;
+        peer_member.channel = channel.key();
+        peer_member.wallet = ctx.accounts.peer.key();
+        peer_member.joined_at = clock.unix_timestamp;
+        peer_member.is_active = true;
+        peer_member.role = MemberRole::Admin;
+        peer_member.key_version = UserKey::version_of(ctx.accounts.peer_key.as_deref());
+        peer_member.last_message_at = 0;
+        peer_member.can_post = false;
+        peer_member.gate_token_account = Pubkey::default();
+        peer_member.bump = ctx.bumps.peer_member;
+
+        msg!("Direct message created: {}", channel.key());
+        msg!("Participants: {} and {}", creator_member.wallet, peer_member.wallet);
+
+        Ok(())
+    }
+
     /// Add member to channel with optional token-gating and staking
     /// If channel has token requirements, tokens are transferred to vault and locked
+    /// PrivateGroup channels are invite-only and must use join_with_invite
     pub fn join_channel(
         ctx: Context<JoinChannel>,
     ) -> Result<()> {
-        let channel = &mut ctx.accounts.channel;
-        let member_account = &mut ctx.accounts.member;
-
-        require!(channel.is_active, ErrorCode::ChannelInactive);
         require!(
-            channel.member_count < MAX_MEMBERS,
-            ErrorCode::ChannelFull
+            ctx.accounts.channel.channel_type != ChannelType::PrivateGroup,
+            ErrorCode::InviteRequired
         );
 
-        // Token-gating with staking (if channel requires it)
-        if let (Some(required_mint), Some(min_amount)) =
-            (channel.required_token_mint, channel.min_token_amount)
-        {
-            // Token account is required for token-gated channels
-            let user_token_account = ctx.accounts.user_token_account
-                .as_ref()
-                .ok_or(ErrorCode::TokenAccountRequired)?;
-
-            // Verify token account belongs to the joining wallet
-            require!(
-                user_token_account.owner == ctx.accounts.member_wallet.key(),
-                ErrorCode::TokenAccountOwnerMismatch
-            );
+        ctx.accounts.join(&ctx.bumps)
+    }
 
-            // Verify token account is for the correct mint
-            require!(
-                user_token_account.mint == required_mint,
-                ErrorCode::TokenMintMismatch
-            );
+    /// Join a channel by presenting an invite
+    /// The invite keypair co-signs the transaction, so its secret never appears
+    /// in instruction data. Token-gating and staking apply exactly as in join_channel
+    pub fn join_with_invite(ctx: Context<JoinWithInvite>) -> Result<()> {
+        let invite = &mut ctx.accounts.invite;
+        let clock = Clock::get()?;
 
-            // Verify sufficient balance
-            require!(
-                user_token_account.amount >= min_amount,
-                ErrorCode::InsufficientTokens
-            );
+        if let Some(expires_at) = invite.expires_at {
+            require!(clock.unix_timestamp < expires_at, ErrorCode::InviteExpired);
+        }
+        if let Some(max_uses) = invite.max_uses {
+            require!(invite.uses < max_uses, ErrorCode::InviteExhausted);
+        }
 
-            // Get vault and stake accounts for token transfer
-            let vault_token_account = ctx.accounts.vault_token_account
-                .as_ref()
-                .ok_or(ErrorCode::TokenAccountRequired)?;
-            let vault = ctx.accounts.token_vault
-                .as_mut()
-                .ok_or(ErrorCode::TokenAccountRequired)?;
-            let stake = ctx.accounts.member_stake
-                .as_mut()
-                .ok_or(ErrorCode::TokenAccountRequired)?;
-            let token_program = ctx.accounts.token_program
-                .as_ref()
-                .ok_or(ErrorCode::TokenAccountRequired)?;
+        invite.uses = invite.uses
+            .checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
 
-            // Transfer tokens from user to vault (user signs, so no PDA signer needed)
-            let transfer_ctx = CpiContext::new(
-                token_program.to_account_info(),
-                Transfer {
-                    from: user_token_account.to_account_info(),
-                    to: vault_token_account.to_account_info(),
-                    authority: ctx.accounts.member_wallet.to_account_info(),
-                },
-            );
-            transfer(transfer_ctx, min_amount)?;
+        msg!("Invite used: {} time(s)", invite.uses);
 
-            // Update vault state
-            vault.total_locked = vault.total_locked
-                .checked_add(min_amount)
-                .ok_or(ErrorCode::Overflow)?;
+        ctx.accounts.join.join(&ctx.bumps.join)
+    }
 
-            // Initialize stake record
-            stake.member = ctx.accounts.member_wallet.key();
-            stake.channel = channel.key();
-            stake.locked_amount = min_amount;
-            stake.lock_timestamp = Clock::get()?.unix_timestamp;
-            stake.bump = ctx.bumps.member_stake.unwrap();
+    /// Create an invite for a channel (admin or higher)
+    /// The invite is a keypair handed out off-chain; only its public key is stored
+    pub fn create_invite(
+        ctx: Context<CreateInvite>,
+        invite_key: Pubkey,
+        expires_at: Option<i64>,
+        max_uses: Option<u32>,
+    ) -> Result<()> {
+        let invite = &mut ctx.accounts.invite;
+        let clock = Clock::get()?;
 
-            msg!("Staked {} tokens to vault", min_amount);
+        if let Some(expires_at) = expires_at {
+            require!(expires_at > clock.unix_timestamp, ErrorCode::InvalidInviteExpiry);
+        }
+        if let Some(max_uses) = max_uses {
+            require!(max_uses > 0, ErrorCode::InvalidInviteMaxUses);
         }
 
-        let clock = Clock::get()?;
+        invite.channel = ctx.accounts.channel.key();
+        invite.creator = ctx.accounts.authority.key();
+        invite.invite_key = invite_key;
+        invite.expires_at = expires_at;
+        invite.max_uses = max_uses;
+        invite.uses = 0;
+        invite.created_at = clock.unix_timestamp;
+        invite.bump = ctx.bumps.invite;
 
-        member_account.channel = channel.key();
-        member_account.wallet = ctx.accounts.member_wallet.key();
-        member_account.joined_at = clock.unix_timestamp;
-        member_account.is_active = true;
-        member_account.bump = ctx.bumps.member;
+        msg!("Invite created for channel: {}", invite.channel);
 
-        channel.member_count += 1;
+        Ok(())
+    }
 
-        msg!("Member joined: {}", member_account.wallet);
-        msg!("Total members: {}", channel.member_count);
+    /// Revoke an invite (admin or higher)
+    /// Closes the Invite PDA and refunds rent to whoever created it
+    pub fn revoke_invite(ctx: Context<RevokeInvite>) -> Result<()> {
+        msg!("Invite revoked for channel: {}", ctx.accounts.invite.channel);
 
         Ok(())
     }
 
     /// Log message hash on-chain (actual message stored off-chain)
     /// This provides proof of message without revealing content
+    /// Replies reference earlier message numbers so threads can be indexed without decrypting
+    /// Pass a message record to be able to edit or delete the message later
     pub fn log_message(
         ctx: Context<LogMessage>,
         message_hash: [u8; 32],
         encrypted_ipfs_cid: Vec<u8>, // Encrypted IPFS CID
+        reply_to: Option<u64>,
+        thread_root: Option<u64>,
     ) -> Result<()> {
         let channel = &mut ctx.accounts.channel;
         let clock = Clock::get()?;
@@ -206,326 +356,3126 @@ pub mod shield_chat {
         require!(channel.is_active, ErrorCode::ChannelInactive);
 
         // Verify sender is channel member
-        let member = &ctx.accounts.member;
+        let member = &mut ctx.accounts.member;
         require!(member.is_active, ErrorCode::MemberNotActive);
         require!(
             member.channel == channel.key(),
             ErrorCode::NotChannelMember
         );
 
-        channel.message_count += 1;
+        require!(channel.can_post(member), ErrorCode::PostingNotAllowed);
+        enforce_slow_mode(channel, member, clock.unix_timestamp)?;
 
-        // Emit event for Helius monitoring
-        emit!(MessageLogged {
-            channel: channel.key(),
-            sender: ctx.accounts.sender.key(),
+        // Replies can only point at messages that already exist
+        for target in [reply_to, thread_root].into_iter().flatten() {
+            require!(
+                target >= 1 && target <= channel.message_count,
+                ErrorCode::InvalidReplyTarget
+            );
+        }
+        if let (Some(reply_to), Some(thread_root)) = (reply_to, thread_root) {
+            require!(thread_root <= reply_to, ErrorCode::InvalidReplyTarget);
+        }
+
+        let message_number = append_message(
+            channel,
+            ctx.accounts.sender.key(),
             message_hash,
             encrypted_ipfs_cid,
-            message_number: channel.message_count,
-            timestamp: clock.unix_timestamp,
-        });
+            reply_to,
+            thread_root,
+            clock.unix_timestamp,
+        )?;
+
+        if let Some(record) = ctx.accounts.message_record.as_mut() {
+            record.channel = channel.key();
+            record.message_number = message_number;
+            record.sender = ctx.accounts.sender.key();
+            record.message_hash = message_hash;
+            record.created_at = clock.unix_timestamp;
+            record.bump = ctx.bumps.message_record.unwrap();
+        }
 
-        msg!("Message logged: #{}", channel.message_count);
+        msg!("Message logged: #{}", message_number);
 
         Ok(())
     }
 
-    /// Update channel settings (owner only)
-    pub fn update_channel(
-        ctx: Context<UpdateChannel>,
-        new_encrypted_metadata: Option<Vec<u8>>,
-        new_is_active: Option<bool>,
+    /// Log several messages in one transaction (up to MAX_BATCH_MESSAGES)
+    /// Each entry gets the next message number and its own MessageLogged event
+    /// Entries are top-level messages without a message record: they cannot be
+    /// replies, and the sender cannot edit or delete them afterwards (moderators
+    /// can still retract them with remove_message). Use log_message for those
+    pub fn log_messages(
+        ctx: Context<LogMessages>,
+        entries: Vec<MessageEntry>,
     ) -> Result<()> {
         let channel = &mut ctx.accounts.channel;
+        let clock = Clock::get()?;
 
-        if let Some(metadata) = new_encrypted_metadata {
-            require!(
-                metadata.len() <= MAX_METADATA_SIZE,
-                ErrorCode::MetadataTooLarge
-            );
-            channel.encrypted_metadata = metadata;
-        }
+        require!(channel.is_active, ErrorCode::ChannelInactive);
+        require!(ctx.accounts.member.is_active, ErrorCode::MemberNotActive);
+        require!(
+            !entries.is_empty() && entries.len() <= MAX_BATCH_MESSAGES,
+            ErrorCode::InvalidBatchSize
+        );
 
-        if let Some(is_active) = new_is_active {
-            channel.is_active = is_active;
+        require!(channel.can_post(&ctx.accounts.member), ErrorCode::PostingNotAllowed);
+
+        // A batch counts as a single post for slow mode
+        enforce_slow_mode(channel, &mut ctx.accounts.member, clock.unix_timestamp)?;
+
+        let first = channel.message_count + 1;
+        for entry in entries {
+            append_message(
+                channel,
+                ctx.accounts.sender.key(),
+                entry.message_hash,
+                entry.encrypted_ipfs_cid,
+                None,
+                None,
+                clock.unix_timestamp,
+            )?;
         }
 
-        msg!("Channel updated: {}", channel.channel_id);
+        msg!("Messages logged: #{}-#{}", first, channel.message_count);
 
         Ok(())
     }
 
-    /// Leave channel (member removes themselves)
-    /// Returns staked tokens if this was a token-gated channel
-    pub fn leave_channel(
-        ctx: Context<LeaveChannel>,
-    ) -> Result<()> {
+    /// Publish a checkpoint that messages up to `up_to` have passed the channel's
+    /// retention period (permissionless)
+    /// Age is proven by the retention mark: a message count recorded at a known
+    /// time, so expiry may trail the exact deadline but never precedes it
+    pub fn expire_messages(ctx: Context<ExpireMessages>, up_to: u64) -> Result<()> {
         let channel = &mut ctx.accounts.channel;
-        let member = &mut ctx.accounts.member;
+        let now = Clock::get()?.unix_timestamp;
+
+        require!(channel.retention_seconds > 0, ErrorCode::RetentionDisabled);
+
+        // Everything at or below the mark was logged no later than the mark time
+        let mark_expired = channel.retention_mark_at > 0
+            && now.saturating_sub(channel.retention_mark_at) >= channel.retention_seconds as i64;
+        let expirable = if mark_expired {
+            channel.retention_mark
+        } else {
+            channel.expired_through
+        };
+        require!(
+            up_to > channel.expired_through && up_to <= expirable,
+            ErrorCode::InvalidExpiryCheckpoint
+        );
 
-        member.is_active = false;
-        channel.member_count = channel.member_count.saturating_sub(1);
+        channel.expired_through = up_to;
 
-        // Return staked tokens if this was a token-gated channel with staking
-        if let Some(stake) = ctx.accounts.member_stake.as_mut() {
-            if stake.locked_amount > 0 {
-                let vault = ctx.accounts.token_vault
-                    .as_mut()
-                    .ok_or(ErrorCode::TokenAccountRequired)?;
-                let vault_token_account = ctx.accounts.vault_token_account
-                    .as_ref()
-                    .ok_or(ErrorCode::TokenAccountRequired)?;
-                let user_token_account = ctx.accounts.user_token_account
-                    .as_ref()
-                    .ok_or(ErrorCode::TokenAccountRequired)?;
-                let vault_authority = ctx.accounts.vault_authority
-                    .as_ref()
-                    .ok_or(ErrorCode::TokenAccountRequired)?;
-                let token_program = ctx.accounts.token_program
-                    .as_ref()
-                    .ok_or(ErrorCode::TokenAccountRequired)?;
+        // Start timing the next batch once the current mark is used up
+        if up_to == channel.retention_mark {
+            if channel.message_count > up_to {
+                channel.retention_mark = channel.message_count;
+                channel.retention_mark_at = now;
+            } else {
+                channel.retention_mark_at = 0;
+            }
+        }
 
-                let locked_amount = stake.locked_amount;
+        emit!(MessagesExpired {
+            channel: channel.key(),
+            up_to_message: up_to,
+            retention_seconds: channel.retention_seconds,
+            timestamp: now,
+        });
 
-                // PDA signer seeds for vault authority
-                let channel_key = channel.key();
-                let mint_key = vault.token_mint;
-                let seeds = &[
-                    VAULT_AUTH_SEED,
-                    channel_key.as_ref(),
-                    mint_key.as_ref(),
-                    &[vault.auth_bump],
-                ];
-                let signer_seeds = &[&seeds[..]];
+        msg!("Messages expired through #{}", up_to);
 
-                // Transfer tokens back to user (vault authority signs as PDA)
-                let transfer_ctx = CpiContext::new_with_signer(
-                    token_program.to_account_info(),
-                    Transfer {
-                        from: vault_token_account.to_account_info(),
-                        to: user_token_account.to_account_info(),
-                        authority: vault_authority.to_account_info(),
-                    },
-                    signer_seeds,
-                );
-                transfer(transfer_ctx, locked_amount)?;
+        Ok(())
+    }
 
-                // Update vault state
-                vault.total_locked = vault.total_locked.saturating_sub(locked_amount);
+    /// Add or remove a reaction on an earlier message
+    /// Reactions are events only and do not advance message_count
+    pub fn react_to_message(
+        ctx: Context<ReactToMessage>,
+        message_number: u64,
+        reaction: Vec<u8>, // Short or encrypted reaction code
+        remove: bool,
+    ) -> Result<()> {
+        let channel = &ctx.accounts.channel;
 
-                // Clear stake record
-                stake.locked_amount = 0;
+        require!(channel.is_active, ErrorCode::ChannelInactive);
+        require!(ctx.accounts.member.is_active, ErrorCode::MemberNotActive);
+        require!(
+            message_number >= 1 && message_number <= channel.message_count,
+            ErrorCode::InvalidMessageNumber
+        );
+        require!(
+            !reaction.is_empty() && reaction.len() <= MAX_REACTION_SIZE,
+            ErrorCode::InvalidReaction
+        );
 
-                msg!("Returned {} tokens to member", locked_amount);
-            }
+        let reactor = ctx.accounts.sender.key();
+        let timestamp = Clock::get()?.unix_timestamp;
+
+        if remove {
+            emit!(ReactionRemoved {
+                channel: channel.key(),
+                reactor,
+                message_number,
+                reaction,
+                timestamp,
+            });
+        } else {
+            emit!(ReactionAdded {
+                channel: channel.key(),
+                reactor,
+                message_number,
+                reaction,
+                timestamp,
+            });
         }
 
-        msg!("Member left: {}", member.wallet);
-        msg!("Remaining members: {}", channel.member_count);
+        msg!("Reaction {} on #{}", if remove { "removed" } else { "added" }, message_number);
 
         Ok(())
     }
 
-    /// Rejoin a channel that was previously left
-    /// Reactivates an existing inactive member account
-    /// For token-gated channels, requires staking tokens again
-    pub fn rejoin_channel(ctx: Context<RejoinChannel>) -> Result<()> {
-        let channel = &mut ctx.accounts.channel;
-        let member = &mut ctx.accounts.member;
+    /// Pin a message for every client (admin or higher)
+    /// The pins account is created on first use, paid by the signer
+    pub fn pin_message(ctx: Context<PinMessage>, message_number: u64) -> Result<()> {
+        let channel = &ctx.accounts.channel;
+        let pins = &mut ctx.accounts.pins;
 
-        require!(channel.is_active, ErrorCode::ChannelInactive);
-        require!(!member.is_active, ErrorCode::MemberAlreadyActive);
         require!(
-            channel.member_count < MAX_MEMBERS,
-            ErrorCode::ChannelFull
+            message_number >= 1 && message_number <= channel.message_count,
+            ErrorCode::InvalidMessageNumber
         );
+        require!(!pins.messages.contains(&message_number), ErrorCode::AlreadyPinned);
+        require!(pins.messages.len() < MAX_PINNED_MESSAGES, ErrorCode::PinLimitReached);
 
-        // Token-gating with staking (if channel requires it)
-        if let (Some(required_mint), Some(min_amount)) =
-            (channel.required_token_mint, channel.min_token_amount)
-        {
-            // Token account is required for token-gated channels
-            let user_token_account = ctx.accounts.user_token_account
-                .as_ref()
-                .ok_or(ErrorCode::TokenAccountRequired)?;
-
-            // Verify token account belongs to the joining wallet
-            require!(
-                user_token_account.owner == ctx.accounts.member_wallet.key(),
-                ErrorCode::TokenAccountOwnerMismatch
-            );
+        pins.channel = channel.key();
+        pins.messages.push(message_number);
+        pins.bump = ctx.bumps.pins;
 
-            // Verify token account is for the correct mint
-            require!(
-                user_token_account.mint == required_mint,
-                ErrorCode::TokenMintMismatch
-            );
+        emit!(MessagePinned {
+            channel: channel.key(),
+            message_number,
+            pinned_by: ctx.accounts.authority.key(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
 
-            // Verify sufficient balance
-            require!(
-                user_token_account.amount >= min_amount,
-                ErrorCode::InsufficientTokens
-            );
+        msg!("Message pinned: #{}", message_number);
 
-            // Get vault and stake accounts for token transfer
-            let vault_token_account = ctx.accounts.vault_token_account
-                .as_ref()
-                .ok_or(ErrorCode::TokenAccountRequired)?;
-            let vault = ctx.accounts.token_vault
-                .as_mut()
-                .ok_or(ErrorCode::TokenAccountRequired)?;
-            let stake = ctx.accounts.member_stake
-                .as_mut()
-                .ok_or(ErrorCode::TokenAccountRequired)?;
-            let token_program = ctx.accounts.token_program
-                .as_ref()
-                .ok_or(ErrorCode::TokenAccountRequired)?;
+        Ok(())
+    }
 
-            // Transfer tokens from user to vault
-            let transfer_ctx = CpiContext::new(
-                token_program.to_account_info(),
-                Transfer {
-                    from: user_token_account.to_account_info(),
-                    to: vault_token_account.to_account_info(),
-                    authority: ctx.accounts.member_wallet.to_account_info(),
-                },
-            );
-            transfer(transfer_ctx, min_amount)?;
+    /// Remove a message from the channel's pins (admin or higher)
+    pub fn unpin_message(ctx: Context<UnpinMessage>, message_number: u64) -> Result<()> {
+        let pins = &mut ctx.accounts.pins;
+
+        let index = pins.messages
+            .iter()
+            .position(|pinned| *pinned == message_number)
+            .ok_or(ErrorCode::NotPinned)?;
+        pins.messages.remove(index);
+
+        emit!(MessageUnpinned {
+            channel: ctx.accounts.channel.key(),
+            message_number,
+            unpinned_by: ctx.accounts.authority.key(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
 
-            // Update vault state
-            vault.total_locked = vault.total_locked
-                .checked_add(min_amount)
-                .ok_or(ErrorCode::Overflow)?;
+        msg!("Message unpinned: #{}", message_number);
 
-            // Update stake record (reuse existing account)
-            stake.locked_amount = min_amount;
-            stake.lock_timestamp = Clock::get()?.unix_timestamp;
+        Ok(())
+    }
 
-            msg!("Staked {} tokens to vault for rejoin", min_amount);
-        }
+    /// Replace a message's content (original sender, while still an active member)
+    /// Only messages logged with a message record can be edited
+    pub fn edit_message(
+        ctx: Context<EditMessage>,
+        new_message_hash: [u8; 32],
+        new_encrypted_ipfs_cid: Vec<u8>,
+    ) -> Result<()> {
+        let channel = &ctx.accounts.channel;
+        let record = &mut ctx.accounts.message_record;
 
-        let clock = Clock::get()?;
+        require!(channel.is_active, ErrorCode::ChannelInactive);
+        require!(ctx.accounts.member.is_active, ErrorCode::MemberNotActive);
 
-        // Reactivate member
-        member.is_active = true;
-        member.joined_at = clock.unix_timestamp;
+        record.message_hash = new_message_hash;
 
-        channel.member_count += 1;
+        emit!(MessageEdited {
+            channel: channel.key(),
+            sender: record.sender,
+            message_number: record.message_number,
+            new_message_hash,
+            new_encrypted_ipfs_cid,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
 
-        msg!("Member rejoined: {}", member.wallet);
-        msg!("Total members: {}", channel.member_count);
+        msg!("Message edited: #{}", record.message_number);
 
         Ok(())
     }
 
-    /// Set token-gating requirements (owner only)
-    pub fn set_token_gate(
-        ctx: Context<SetTokenGate>,
-        required_token_mint: Pubkey,
-        min_token_amount: u64,
-    ) -> Result<()> {
-        let channel = &mut ctx.accounts.channel;
-
-        channel.required_token_mint = Some(required_token_mint);
-        channel.min_token_amount = Some(min_token_amount);
+    /// Retract a message (original sender, or moderator or higher)
+    /// Closes the message record, refunding rent to the sender, so it cannot be edited again
+    pub fn delete_message(ctx: Context<DeleteMessage>) -> Result<()> {
+        let record = &ctx.accounts.message_record;
+
+        emit!(MessageDeleted {
+            channel: ctx.accounts.channel.key(),
+            sender: record.sender,
+            message_number: record.message_number,
+            deleted_by: ctx.accounts.authority.key(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
 
-        msg!("Token gate set: {} tokens required", min_token_amount);
-        msg!("Token mint: {}", required_token_mint);
+        msg!("Message deleted: #{}", record.message_number);
 
         Ok(())
     }
 
-    /// Initialize token vault for staking (owner only, after set_token_gate)
-    /// Creates a vault PDA and associated token account to hold staked tokens
-    pub fn initialize_vault(ctx: Context<InitializeVault>) -> Result<()> {
-        let channel = &ctx.accounts.channel;
-        let vault = &mut ctx.accounts.token_vault;
-
+    /// Retract a message that was logged without a record (moderator or higher)
+    /// The sender is not stored on chain for these, so the event reports the
+    /// default key and indexers match the message by number
+    pub fn remove_message(ctx: Context<RemoveMessage>, message_number: u64) -> Result<()> {
         require!(
-            channel.required_token_mint.is_some(),
-            ErrorCode::NotTokenGatedChannel
+            message_number >= 1 && message_number <= ctx.accounts.channel.message_count,
+            ErrorCode::InvalidMessageNumber
         );
 
-        vault.channel = channel.key();
-        vault.token_mint = channel.required_token_mint.unwrap();
-        vault.total_locked = 0;
-        vault.bump = ctx.bumps.token_vault;
-        vault.auth_bump = ctx.bumps.vault_authority;
+        emit!(MessageDeleted {
+            channel: ctx.accounts.channel.key(),
+            sender: Pubkey::default(),
+            message_number,
+            deleted_by: ctx.accounts.authority.key(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
 
-        msg!("Token vault initialized for channel: {}", channel.key());
-        msg!("Vault token account: {}", ctx.accounts.vault_token_account.key());
+        msg!("Message removed: #{}", message_number);
 
         Ok(())
     }
-}
-
-// ==================== ACCOUNTS ====================
 
-#[derive(Accounts)]
-#[instruction(channel_id: u64)]
-pub struct CreateChannel<'info> {
-    #[account(
-        init,
-        payer = owner,
-        space = 8 + Channel::LEN,
-        seeds = [CHANNEL_SEED, owner.key().as_ref(), channel_id.to_le_bytes().as_ref()],
-        bump
-    )]
-    pub channel: Account<'info, Channel>,
+    /// Update channel settings (admin or higher)
+    /// The account is resized to fit new metadata and rent is settled with the owner,
+    /// who paid for the channel; growing it therefore needs the owner's signature
+    pub fn update_channel(
+        ctx: Context<UpdateChannel>,
+        new_encrypted_metadata: Option<Vec<u8>>,
+        new_is_active: Option<bool>,
+        new_max_members: Option<u16>,
+        new_slow_mode_seconds: Option<u32>,
+        new_posting_policy: Option<PostingPolicy>,
+        new_retention_seconds: Option<u32>,
+    ) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+
+        if let Some(metadata) = new_encrypted_metadata {
+            require!(
+                metadata.len() <= MAX_METADATA_SIZE,
+                ErrorCode::MetadataTooLarge
+            );
+            channel.encrypted_metadata = metadata;
+        }
+
+        if let Some(is_active) = new_is_active {
+            channel.is_active = is_active;
+        }
+
+        if let Some(max_members) = new_max_members {
+            // Can be lowered to the current size but never below it
+            require!(
+                channel.channel_type != ChannelType::DirectMessage
+                    && max_members >= channel.member_count.max(1)
+                    && max_members <= MAX_MEMBERS,
+                ErrorCode::InvalidMemberLimit
+            );
+            channel.max_members = max_members;
+        }
+
+        if let Some(slow_mode_seconds) = new_slow_mode_seconds {
+            channel.slow_mode_seconds = slow_mode_seconds;
+        }
+
+        if let Some(posting_policy) = new_posting_policy {
+            channel.posting_policy = posting_policy;
+        }
+
+        if let Some(retention_seconds) = new_retention_seconds {
+            channel.retention_seconds = retention_seconds;
+        }
+
+        msg!("Channel updated: {}", channel.channel_id);
+
+        Ok(())
+    }
+
+    /// Bring a channel created by an older program version up to the current layout
+    /// Grows the account to fit the current layout (payer tops up rent) and fills in
+    /// defaults for fields that did not exist yet. Older create_channel counted an
+    /// owner seat without a Member account, so one is created to back it and the
+    /// seat can be vacated later. Permissionless and idempotent
+    pub fn migrate_channel(ctx: Context<MigrateChannel>) -> Result<()> {
+        let info = ctx.accounts.channel.to_account_info();
+        let new_len = Channel::space(Channel::stored_metadata_len(&info.try_borrow_data()?)?);
+
+        grow_account(&info, new_len, &ctx.accounts.payer, &ctx.accounts.system_program)?;
+
+        // New trailing bytes are zeroed, so newer fields deserialize as defaults
+        let mut channel = Channel::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+        if channel.max_members == 0 {
+            channel.max_members = if channel.channel_type == ChannelType::DirectMessage {
+                DM_MEMBERS
+            } else {
+                DEFAULT_MAX_MEMBERS
+            };
+
+            let owner_member_info = ctx.accounts.owner_member.to_account_info();
+            let (address, bump) = Pubkey::find_program_address(
+                &[MEMBER_SEED, info.key.as_ref(), channel.owner.as_ref()],
+                &crate::ID,
+            );
+            require_keys_eq!(
+                owner_member_info.key(),
+                address,
+                anchor_lang::error::ErrorCode::ConstraintSeeds
+            );
+
+            if owner_member_info.data_is_empty() {
+                create_program_account(
+                    &owner_member_info,
+                    &ctx.accounts.payer,
+                    &ctx.accounts.system_program,
+                    8 + Member::LEN,
+                    &[MEMBER_SEED, info.key.as_ref(), channel.owner.as_ref(), &[bump]],
+                )?;
+
+                let owner_member = Member {
+                    channel: info.key(),
+                    wallet: channel.owner,
+                    joined_at: channel.created_at,
+                    is_active: true,
+                    bump,
+                    role: MemberRole::Owner,
+                    key_version: 0,
+                    last_message_at: 0,
+                    can_post: false,
+                    gate_token_account: Pubkey::default(),
+                };
+                owner_member.try_serialize(&mut &mut owner_member_info.try_borrow_mut_data()?[..])?;
+
+                msg!("Owner seat backed by member account: {}", address);
+            }
+        }
+        channel.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+
+        msg!("Channel migrated: {}", channel.channel_id);
+        msg!("Member limit: {}", channel.max_members);
+
+        Ok(())
+    }
+
+    /// Bring a member record created by an older program version up to the
+    /// current layout. Newer fields start zeroed: a plain Member role with no
+    /// posting allowlist entry. Permissionless and idempotent
+    pub fn migrate_member(ctx: Context<MigrateMember>) -> Result<()> {
+        let info = ctx.accounts.member.to_account_info();
+
+        grow_account(&info, 8 + Member::LEN, &ctx.accounts.payer, &ctx.accounts.system_program)?;
+
+        let member = Member::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+
+        msg!("Member migrated: {}", member.wallet);
+
+        Ok(())
+    }
+
+    /// Bring a vault created before staking rewards up to the current layout
+    /// The reward index starts at zero, so no rewards are owed for time before it
+    /// existed. Permissionless and idempotent
+    pub fn migrate_vault(ctx: Context<MigrateVault>) -> Result<()> {
+        let info = ctx.accounts.token_vault.to_account_info();
+
+        grow_account(&info, 8 + TokenVault::LEN, &ctx.accounts.payer, &ctx.accounts.system_program)?;
+
+        let vault = TokenVault::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+
+        msg!("Vault migrated for channel: {}", vault.channel);
+
+        Ok(())
+    }
+
+    /// Bring a stake record created before staking rewards up to the current layout
+    /// The reward checkpoint starts at zero: the stake was locked before any reward
+    /// was funded, so it earns from the start of the vault's index. The recorded
+    /// lock reads as zero, so a migrated stake can leave without penalty
+    /// Permissionless and idempotent
+    pub fn migrate_stake(ctx: Context<MigrateStake>) -> Result<()> {
+        let info = ctx.accounts.member_stake.to_account_info();
+
+        grow_account(&info, 8 + MemberStake::LEN, &ctx.accounts.payer, &ctx.accounts.system_program)?;
+
+        let stake = MemberStake::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+
+        msg!("Stake migrated: {}", stake.member);
+
+        Ok(())
+    }
+
+    /// Raise a member's role (admin or higher)
+    /// The signer can only grant roles below their own, so Owner is never granted here
+    pub fn promote_member(
+        ctx: Context<ChangeMemberRole>,
+        new_role: MemberRole,
+    ) -> Result<()> {
+        let authority_role = ctx.accounts.channel
+            .role_of(&ctx.accounts.authority.key(), ctx.accounts.authority_member.as_deref())
+            .ok_or(ErrorCode::InsufficientRole)?;
+        let member = &mut ctx.accounts.member;
+
+        require!(member.is_active, ErrorCode::MemberNotActive);
+        require!(new_role > member.role, ErrorCode::InvalidRoleChange);
+        require!(authority_role > new_role, ErrorCode::InsufficientRole);
+
+        member.role = new_role;
+
+        msg!("Member promoted: {} -> {:?}", member.wallet, new_role);
+
+        Ok(())
+    }
+
+    /// Add or remove a member from the channel's poster allowlist (admin or higher)
+    /// Only consulted when the posting policy is Allowlist
+    pub fn set_member_posting(
+        ctx: Context<SetMemberPosting>,
+        can_post: bool,
+    ) -> Result<()> {
+        let member = &mut ctx.accounts.member;
+
+        member.can_post = can_post;
+
+        msg!("Member posting: {} -> {}", member.wallet, can_post);
+
+        Ok(())
+    }
+
+    /// Lower a member's role (admin or higher)
+    /// The signer must outrank the member's current role
+    pub fn demote_member(
+        ctx: Context<ChangeMemberRole>,
+        new_role: MemberRole,
+    ) -> Result<()> {
+        let authority_role = ctx.accounts.channel
+            .role_of(&ctx.accounts.authority.key(), ctx.accounts.authority_member.as_deref())
+            .ok_or(ErrorCode::InsufficientRole)?;
+        let member = &mut ctx.accounts.member;
+
+        require!(new_role < member.role, ErrorCode::InvalidRoleChange);
+        require!(authority_role > member.role, ErrorCode::InsufficientRole);
+
+        member.role = new_role;
+
+        msg!("Member demoted: {} -> {:?}", member.wallet, new_role);
+
+        Ok(())
+    }
+
+    /// Propose a new channel owner (owner only)
+    /// The proposed wallet becomes owner once it calls accept_ownership; None cancels
+    pub fn propose_owner(
+        ctx: Context<ProposeOwner>,
+        new_owner: Option<Pubkey>,
+    ) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+
+        // Unmigrated channels may count an owner seat with no Member account,
+        // which a new owner could never vacate
+        require!(channel.max_members != 0, ErrorCode::ChannelNotMigrated);
+
+        channel.pending_owner = new_owner;
+
+        match new_owner {
+            Some(new_owner) => msg!("Ownership proposed to: {}", new_owner),
+            None => msg!("Ownership proposal cancelled"),
+        }
+
+        Ok(())
+    }
+
+    /// Accept a pending ownership transfer (pending owner only)
+    /// The new owner must be an active member; the previous owner stays on as admin,
+    /// or drops to a plain member if they already left
+    pub fn accept_ownership(ctx: Context<AcceptOwnership>) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+        let new_owner_member = &mut ctx.accounts.new_owner_member;
+
+        require!(new_owner_member.is_active, ErrorCode::MemberNotActive);
+
+        // Demote whatever record the previous owner has, active or not, so no
+        // Member account outside the current owner's keeps the Owner role
+        let info = ctx.accounts.previous_owner_member.to_account_info();
+        if !info.data_is_empty() {
+            let mut previous_owner_member = Member::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+            previous_owner_member.role = if previous_owner_member.is_active {
+                MemberRole::Admin
+            } else {
+                MemberRole::Member
+            };
+            previous_owner_member.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+        }
+
+        let previous_owner = channel.owner;
+        channel.owner = new_owner_member.wallet;
+        channel.pending_owner = None;
+        new_owner_member.role = MemberRole::Owner;
+
+        msg!("Ownership transferred: {} -> {}", previous_owner, channel.owner);
+
+        Ok(())
+    }
+
+    /// Leave channel (member removes themselves)
+    /// Returns staked tokens if this was a token-gated channel
+    /// If the owner leaves while others remain, ownership passes to the given
+    /// successor, which must be an active admin. An owner who is the last member
+    /// keeps ownership so the channel can still be administered and closed
+    /// Roles and posting rights are dropped, so a rejoin starts as a plain member
+    pub fn leave_channel(
+        ctx: Context<LeaveChannel>,
+    ) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+        let member = &mut ctx.accounts.member;
+
+        require!(member.is_active, ErrorCode::MemberNotActive);
+
+        // Owner succession
+        if member.wallet == channel.owner && channel.member_count > 1 {
+            let successor = ctx.accounts.successor
+                .as_mut()
+                .ok_or(ErrorCode::SuccessorRequired)?;
+            require!(
+                successor.is_active
+                    && successor.role == MemberRole::Admin
+                    && successor.wallet != member.wallet,
+                ErrorCode::InvalidSuccessor
+            );
+
+            channel.owner = successor.wallet;
+            channel.pending_owner = None;
+            successor.role = MemberRole::Owner;
+
+            msg!("Ownership passed to successor: {}", channel.owner);
+        }
+
+        member.is_active = false;
+        member.role = MemberRole::Member;
+        member.can_post = false;
+        channel.member_count = channel.member_count.saturating_sub(1);
+
+        // Return staked tokens if this was a token-gated channel with staking
+        let stake_info = ctx.accounts.member_stake.to_account_info();
+        if !stake_info.data_is_empty() {
+            let stake = &mut load_stake(&stake_info)?;
+
+            // Settle rewards first, while the full stake still counts
+            let rewarded = pay_rewards(
+                stake,
+                ctx.accounts.token_vault.as_mut(),
+                ctx.accounts.vault_authority.as_ref(),
+                ctx.accounts.vault_token_account.as_ref(),
+                ctx.accounts.user_token_account.as_ref(),
+                ctx.accounts.token_mint.as_ref(),
+                ctx.accounts.token_program.as_ref(),
+            )?;
+            if rewarded > 0 {
+                msg!("Paid {} reward tokens to member", rewarded);
+            }
+
+            // Leaving before the lock period ends forfeits part of the stake
+            let now = Clock::get()?.unix_timestamp;
+            let penalty = stake.early_exit_penalty(now);
+            if penalty > 0 {
+                let vault = ctx.accounts.token_vault
+                    .as_mut()
+                    .ok_or(ErrorCode::TokenAccountRequired)?;
+
+                if channel.treasury.is_some() {
+                    let treasury = ctx.accounts.treasury_token_account
+                        .as_ref()
+                        .ok_or(ErrorCode::TreasuryRequired)?;
+                    transfer_from_vault(
+                        vault,
+                        ctx.accounts.vault_authority.as_ref(),
+                        ctx.accounts.vault_token_account.as_ref(),
+                        Some(treasury),
+                        ctx.accounts.token_mint.as_ref(),
+                        ctx.accounts.token_program.as_ref(),
+                        penalty,
+                    )?;
+                }
+
+                // Without a treasury the penalty stays in the vault, outside total_locked
+                vault.total_locked = vault.total_locked.saturating_sub(penalty);
+                stake.locked_amount -= penalty;
+
+                emit!(StakePenalized {
+                    channel: channel.key(),
+                    member: member.wallet,
+                    penalty,
+                    returned: stake.locked_amount,
+                    treasury: channel.treasury,
+                    lock_ends_at: stake.lock_ends_at,
+                    timestamp: now,
+                });
+
+                msg!("Early-exit penalty: {} tokens", penalty);
+            }
+
+            let returned = release_stake(
+                stake,
+                ctx.accounts.token_vault.as_mut(),
+                ctx.accounts.vault_authority.as_ref(),
+                ctx.accounts.vault_token_account.as_ref(),
+                ctx.accounts.user_token_account.as_ref(),
+                ctx.accounts.token_mint.as_ref(),
+                ctx.accounts.token_program.as_ref(),
+            )?;
+            if returned > 0 {
+                msg!("Returned {} tokens to member", returned);
+            }
+
+            store_stake(&stake_info, stake)?;
+        }
+
+        msg!("Member left: {}", member.wallet);
+        msg!("Remaining members: {}", channel.member_count);
+
+        Ok(())
+    }
+
+    /// Remove a member from the channel (moderator or higher)
+    /// Any staked tokens and accrued rewards are paid to the member; they may rejoin later
+    pub fn kick_member(ctx: Context<KickMember>) -> Result<()> {
+        require!(ctx.accounts.member.is_active, ErrorCode::MemberNotActive);
+
+        ctx.accounts.remove()?;
+
+        msg!("Member kicked: {}", ctx.accounts.member.wallet);
+        msg!("Remaining members: {}", ctx.accounts.channel.member_count);
+
+        Ok(())
+    }
+
+    /// Remove a member and record a ban so they cannot join or rejoin (moderator or higher)
+    /// Works on members who already left, in which case only the ban is recorded
+    pub fn ban_member(ctx: Context<BanMember>) -> Result<()> {
+        ctx.accounts.kick.remove()?;
+
+        let ban = &mut ctx.accounts.ban;
+        ban.channel = ctx.accounts.kick.channel.key();
+        ban.wallet = ctx.accounts.kick.member.wallet;
+        ban.banned_by = ctx.accounts.kick.authority.key();
+        ban.banned_at = Clock::get()?.unix_timestamp;
+        ban.bump = ctx.bumps.ban;
+
+        msg!("Member banned: {}", ban.wallet);
+
+        Ok(())
+    }
+
+    /// Forfeit part or all of a member's stake to the channel treasury (admin or higher)
+    /// Pass None to slash the whole stake. The signer must outrank the member;
+    /// with deactivate set the member is also removed, and any stake left over
+    /// can be recovered with close_stake
+    pub fn slash_stake(
+        ctx: Context<SlashStake>,
+        amount: Option<u64>,
+        reason: u8,
+        deactivate: bool,
+    ) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+        let member = &mut ctx.accounts.member;
+        let stake = &mut ctx.accounts.member_stake;
+        let vault = &mut ctx.accounts.token_vault;
+
+        let authority_role = channel
+            .role_of(&ctx.accounts.authority.key(), ctx.accounts.authority_member.as_deref())
+            .ok_or(ErrorCode::InsufficientRole)?;
+        require!(authority_role > member.role, ErrorCode::InsufficientRole);
+
+        stake.settle_rewards(vault)?;
+
+        let amount = amount.unwrap_or(stake.locked_amount);
+        require!(
+            amount > 0 && amount <= stake.locked_amount,
+            ErrorCode::InvalidSlashAmount
+        );
+
+        transfer_from_vault(
+            vault,
+            Some(&ctx.accounts.vault_authority),
+            Some(&ctx.accounts.vault_token_account),
+            Some(&ctx.accounts.treasury_token_account),
+            Some(&ctx.accounts.token_mint),
+            Some(&ctx.accounts.token_program),
+            amount,
+        )?;
+
+        vault.total_locked = vault.total_locked.saturating_sub(amount);
+        stake.locked_amount -= amount;
+
+        let deactivated = deactivate && member.is_active;
+        if deactivated {
+            member.is_active = false;
+            member.role = MemberRole::Member;
+            member.can_post = false;
+            channel.member_count = channel.member_count.saturating_sub(1);
+        }
+
+        emit!(StakeSlashed {
+            channel: channel.key(),
+            member: member.wallet,
+            amount,
+            remaining: stake.locked_amount,
+            reason,
+            deactivated,
+            slashed_by: ctx.accounts.authority.key(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Slashed {} tokens from {} (reason {})", amount, member.wallet, reason);
+        if deactivated {
+            msg!("Remaining members: {}", channel.member_count);
+        }
+
+        Ok(())
+    }
+
+    /// Lift a ban (moderator or higher)
+    /// Closes the Ban PDA and refunds rent to whoever created it
+    pub fn unban_member(ctx: Context<UnbanMember>) -> Result<()> {
+        msg!("Member unbanned: {}", ctx.accounts.ban.wallet);
+
+        Ok(())
+    }
+
+    /// Close a channel and reclaim its rent (owner only)
+    /// Every seat must be vacated and the vault must hold no stakes or rewards owed
+    /// to stakers; the vault, its token account and any stray or unreleased
+    /// tokens in it go back to the owner.
+    /// Invites, bans, message records, key envelopes and pins are seeded by
+    /// created_at, so a direct message reopened later does not inherit them
+    pub fn close_channel(ctx: Context<CloseChannel>) -> Result<()> {
+        let channel_key = ctx.accounts.channel.key();
+        let vault_info = ctx.accounts.token_vault.to_account_info();
+
+        if !vault_info.data_is_empty() {
+            require_keys_eq!(*vault_info.owner, crate::ID, ErrorCode::InvalidVaultAccount);
+            let vault = TokenVault::try_deserialize(&mut &vault_info.try_borrow_data()?[..])?;
+
+            require!(vault.total_locked == 0, ErrorCode::VaultNotEmpty);
+            require!(vault.rewards_owed == 0, ErrorCode::RewardsUnclaimed);
+
+            let vault_token_account = ctx.accounts.vault_token_account
+                .as_ref()
+                .ok_or(ErrorCode::TokenAccountRequired)?;
+            let vault_authority = ctx.accounts.vault_authority
+                .as_ref()
+                .ok_or(ErrorCode::TokenAccountRequired)?;
+            let token_program = ctx.accounts.token_program
+                .as_ref()
+                .ok_or(ErrorCode::TokenAccountRequired)?;
+
+            require_keys_eq!(
+                vault_authority.key(),
+                vault.authority()?,
+                ErrorCode::InvalidVaultAccount
+            );
+
+            let mint_key = vault.token_mint;
+            let seeds = &[
+                VAULT_AUTH_SEED,
+                channel_key.as_ref(),
+                mint_key.as_ref(),
+                &[vault.auth_bump],
+            ];
+            let signer_seeds = &[&seeds[..]];
+
+            // Sweep tokens that were sent to the vault outside of staking,
+            // including rewards that were never released to a staker
+            if vault_token_account.amount > 0 {
+                let owner_token_account = ctx.accounts.owner_token_account
+                    .as_ref()
+                    .ok_or(ErrorCode::TokenAccountRequired)?;
+                let token_mint = ctx.accounts.token_mint
+                    .as_ref()
+                    .ok_or(ErrorCode::TokenAccountRequired)?;
+                require_keys_eq!(token_mint.key(), mint_key, ErrorCode::TokenMintMismatch);
+
+                let transfer_ctx = CpiContext::new_with_signer(
+                    token_program.to_account_info(),
+                    TransferChecked {
+                        from: vault_token_account.to_account_info(),
+                        mint: token_mint.to_account_info(),
+                        to: owner_token_account.to_account_info(),
+                        authority: vault_authority.to_account_info(),
+                    },
+                    signer_seeds,
+                );
+                transfer_checked(transfer_ctx, vault_token_account.amount, token_mint.decimals)?;
+
+                msg!("Swept {} stray tokens to owner", vault_token_account.amount);
+            }
+
+            let close_ctx = CpiContext::new_with_signer(
+                token_program.to_account_info(),
+                CloseAccount {
+                    account: vault_token_account.to_account_info(),
+                    destination: ctx.accounts.owner.to_account_info(),
+                    authority: vault_authority.to_account_info(),
+                },
+                signer_seeds,
+            );
+            close_account(close_ctx)?;
+
+            close_program_account(&vault_info, &ctx.accounts.owner.to_account_info())?;
+
+            msg!("Token vault closed");
+        }
+
+        msg!("Channel closed: {}", ctx.accounts.channel.channel_id);
+
+        Ok(())
+    }
+
+    /// Close a left member's account and reclaim its rent
+    /// The stake record must be closed first so no locked tokens can be orphaned
+    pub fn close_member(ctx: Context<CloseMember>) -> Result<()> {
+        msg!("Member account closed: {}", ctx.accounts.member.wallet);
+
+        Ok(())
+    }
+
+    /// Close a left member's stake record and reclaim its rent
+    /// Any tokens still locked (e.g. left over after slash_stake removed the
+    /// member) are returned to the member before the record is closed
+    pub fn close_stake(ctx: Context<CloseStake>) -> Result<()> {
+        // Closing the record would otherwise drop unclaimed rewards
+        let rewarded = pay_rewards(
+            &mut ctx.accounts.member_stake,
+            ctx.accounts.token_vault.as_mut(),
+            ctx.accounts.vault_authority.as_ref(),
+            ctx.accounts.vault_token_account.as_ref(),
+            ctx.accounts.user_token_account.as_ref(),
+            ctx.accounts.token_mint.as_ref(),
+            ctx.accounts.token_program.as_ref(),
+        )?;
+        if rewarded > 0 {
+            msg!("Paid {} reward tokens to member", rewarded);
+        }
+
+        // Tokens still locked stay put until the lock period ends, so closing
+        // the record cannot skip the early-exit penalty
+        let stake = &ctx.accounts.member_stake;
+        if stake.locked_amount > 0 {
+            require!(
+                Clock::get()?.unix_timestamp >= stake.lock_ends_at,
+                ErrorCode::StakeLocked
+            );
+        }
+
+        let returned = release_stake(
+            &mut ctx.accounts.member_stake,
+            ctx.accounts.token_vault.as_mut(),
+            ctx.accounts.vault_authority.as_ref(),
+            ctx.accounts.vault_token_account.as_ref(),
+            ctx.accounts.user_token_account.as_ref(),
+            ctx.accounts.token_mint.as_ref(),
+            ctx.accounts.token_program.as_ref(),
+        )?;
+        if returned > 0 {
+            msg!("Returned {} tokens to member", returned);
+        }
+
+        msg!("Stake record closed: {}", ctx.accounts.member_stake.member);
+
+        Ok(())
+    }
+
+    /// Rejoin a channel that was previously left
+    /// Reactivates an existing inactive member account
+    /// For token-gated channels, requires staking tokens again
+    pub fn rejoin_channel(ctx: Context<RejoinChannel>) -> Result<()> {
+        require!(ctx.accounts.channel.is_active, ErrorCode::ChannelInactive);
+        require!(!ctx.accounts.member.is_active, ErrorCode::MemberAlreadyActive);
+        require!(
+            ctx.accounts.channel.member_count < ctx.accounts.channel.member_limit(),
+            ErrorCode::ChannelFull
+        );
+
+        // Only the two original participants hold Member accounts for a DM
+        // created by create_direct_message; the cap also covers older DMs
+        if ctx.accounts.channel.channel_type == ChannelType::DirectMessage {
+            require!(
+                ctx.accounts.channel.member_count < DM_MEMBERS,
+                ErrorCode::DirectMessageFull
+            );
+        }
+
+        // A stake left behind on exit must be recovered with close_stake first,
+        // otherwise restaking would overwrite it
+        if let Some(stake) = ctx.accounts.member_stake.as_ref() {
+            require!(stake.locked_amount == 0, ErrorCode::StakeStillLocked);
+        }
+
+        verify_collection_nft(
+            &ctx.accounts.channel,
+            &ctx.accounts.member_wallet,
+            ctx.accounts.nft_token_account.as_ref(),
+            ctx.accounts.nft_metadata.as_ref(),
+        )?;
+
+        // Token-gating with staking (if channel requires it)
+        if let Some(staked) = stake_for_membership(
+            &ctx.accounts.channel,
+            &ctx.accounts.member_wallet,
+            ctx.accounts.user_token_account.as_ref(),
+            ctx.accounts.vault_token_account.as_mut(),
+            ctx.accounts.token_vault.as_mut(),
+            ctx.accounts.token_mint.as_ref(),
+            ctx.accounts.token_program.as_ref(),
+        )? {
+            let stake = ctx.accounts.member_stake
+                .as_mut()
+                .ok_or(ErrorCode::TokenAccountRequired)?;
+
+            // Rewards released before this stake do not accrue to it
+            let vault = ctx.accounts.token_vault
+                .as_mut()
+                .ok_or(ErrorCode::TokenAccountRequired)?;
+            stake.settle_rewards(vault)?;
+
+            // Update stake record (reused, or recreated if it was closed)
+            stake.member = ctx.accounts.member_wallet.key();
+            stake.channel = ctx.accounts.channel.key();
+            stake.lock(&ctx.accounts.channel, staked, Clock::get()?.unix_timestamp);
+            stake.bump = ctx.bumps.member_stake.ok_or(ErrorCode::TokenAccountRequired)?;
+
+            msg!("Staked {} tokens to vault for rejoin", staked);
+        }
+
+        let channel = &mut ctx.accounts.channel;
+        let member = &mut ctx.accounts.member;
+        let clock = Clock::get()?;
+
+        // Reactivate member without any role or allowlist entry held before leaving
+        member.is_active = true;
+        member.joined_at = clock.unix_timestamp;
+        member.role = if member.wallet == channel.owner {
+            MemberRole::Owner
+        } else {
+            MemberRole::Member
+        };
+        member.can_post = false;
+        member.key_version = UserKey::version_of(ctx.accounts.user_key.as_deref());
+        member.gate_token_account = ctx.accounts.user_token_account
+            .as_ref()
+            .map_or(Pubkey::default(), |account| account.key());
+
+        channel.member_count += 1;
+
+        msg!("Member rejoined: {}", member.wallet);
+        msg!("Total members: {}", channel.member_count);
+
+        Ok(())
+    }
+
+    /// Start a new channel key epoch (admin or higher)
+    /// Envelopes for the new key are uploaded in the same call as remaining
+    /// accounts, one (member, envelope) pair per wrapped key. Call this after
+    /// members leave or are kicked so they cannot read later messages
+    pub fn rotate_channel_key<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ManageChannelKey<'info>>,
+        wrapped_keys: Vec<WrappedKey>,
+    ) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+
+        channel.key_epoch = channel.key_epoch
+            .checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
+
+        write_key_envelopes(
+            channel,
+            ctx.remaining_accounts,
+            wrapped_keys,
+            &ctx.accounts.authority,
+            &ctx.accounts.system_program,
+        )?;
+
+        emit!(ChannelKeyRotated {
+            channel: channel.key(),
+            key_epoch: channel.key_epoch,
+            rotated_by: ctx.accounts.authority.key(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Channel key rotated: epoch {}", channel.key_epoch);
+
+        Ok(())
+    }
+
+    /// Upload envelopes for the current key epoch (admin or higher)
+    /// Used for new joiners and for epochs with too many members for one transaction
+    pub fn add_key_envelopes<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ManageChannelKey<'info>>,
+        wrapped_keys: Vec<WrappedKey>,
+    ) -> Result<()> {
+        write_key_envelopes(
+            &ctx.accounts.channel,
+            ctx.remaining_accounts,
+            wrapped_keys,
+            &ctx.accounts.authority,
+            &ctx.accounts.system_program,
+        )?;
+
+        msg!("Key envelopes added: epoch {}", ctx.accounts.channel.key_epoch);
+
+        Ok(())
+    }
+
+    /// Close a key envelope nobody needs any more, refunding its rent to the payer
+    /// Allowed once the channel has rotated past the envelope's epoch or has been
+    /// closed (and possibly reopened). Anyone can call it, the rent only goes back
+    /// to whoever uploaded the envelope
+    pub fn close_key_envelope(ctx: Context<CloseKeyEnvelope>) -> Result<()> {
+        let envelope = &ctx.accounts.key_envelope;
+        let channel_info = ctx.accounts.channel.to_account_info();
+
+        if !channel_info.data_is_empty() {
+            let channel = Channel::try_deserialize(&mut &channel_info.try_borrow_data()?[..])?;
+            // Envelopes written before the current incarnation was created are stale too
+            require!(
+                envelope.key_epoch < channel.key_epoch || envelope.created_at < channel.created_at,
+                ErrorCode::KeyEnvelopeInUse
+            );
+        }
+
+        msg!("Key envelope closed: epoch {} for {}", envelope.key_epoch, envelope.wallet);
+
+        Ok(())
+    }
+
+    /// Publish the wallet's X25519 encryption public key
+    /// Clients wrap channel keys to this key instead of deriving them from the PDA
+    pub fn publish_user_key(
+        ctx: Context<PublishUserKey>,
+        encryption_key: [u8; 32],
+    ) -> Result<()> {
+        let user_key = &mut ctx.accounts.user_key;
+
+        user_key.wallet = ctx.accounts.wallet.key();
+        user_key.encryption_key = encryption_key;
+        user_key.version = 1;
+        user_key.updated_at = Clock::get()?.unix_timestamp;
+        user_key.is_revoked = false;
+        user_key.bump = ctx.bumps.user_key;
+
+        msg!("User key published: {}", user_key.wallet);
+
+        Ok(())
+    }
+
+    /// Replace the wallet's encryption key with a new one (also un-revokes)
+    /// Bumps the version so members can tell which key their envelopes target
+    pub fn rotate_user_key(
+        ctx: Context<UpdateUserKey>,
+        encryption_key: [u8; 32],
+    ) -> Result<()> {
+        let user_key = &mut ctx.accounts.user_key;
+
+        user_key.encryption_key = encryption_key;
+        user_key.version = user_key.version
+            .checked_add(1)
+            .ok_or(ErrorCode::Overflow)?;
+        user_key.updated_at = Clock::get()?.unix_timestamp;
+        user_key.is_revoked = false;
+
+        msg!("User key rotated: {} (version {})", user_key.wallet, user_key.version);
+
+        Ok(())
+    }
+
+    /// Revoke the wallet's encryption key, e.g. after a device compromise
+    /// Clients must stop wrapping keys to it until it is rotated
+    pub fn revoke_user_key(ctx: Context<UpdateUserKey>) -> Result<()> {
+        let user_key = &mut ctx.accounts.user_key;
+
+        require!(!user_key.is_revoked, ErrorCode::UserKeyRevoked);
+
+        user_key.encryption_key = [0; 32];
+        user_key.updated_at = Clock::get()?.unix_timestamp;
+        user_key.is_revoked = true;
+
+        msg!("User key revoked: {} (version {})", user_key.wallet, user_key.version);
+
+        Ok(())
+    }
+
+    /// Set token-gating requirements (admin or higher)
+    /// Stake mode escrows min_token_amount in the vault on join; Hold mode only
+    /// checks the balance, and revalidate_member removes members who drop below it
+    pub fn set_token_gate(
+        ctx: Context<SetTokenGate>,
+        required_token_mint: Pubkey,
+        min_token_amount: u64,
+        gate_mode: GateMode,
+    ) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+
+        channel.required_token_mint = Some(required_token_mint);
+        channel.min_token_amount = Some(min_token_amount);
+        channel.gate_mode = gate_mode;
+
+        msg!("Token gate set: {} tokens required ({:?})", min_token_amount, gate_mode);
+        msg!("Token mint: {}", required_token_mint);
+
+        Ok(())
+    }
+
+    /// Remove a member of a hold-to-join channel whose gate balance dropped
+    /// below the requirement (permissionless)
+    /// Checks the token account the member joined with; a closed or transferred
+    /// account counts as an empty balance
+    pub fn revalidate_member(ctx: Context<RevalidateMember>) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+        let member = &mut ctx.accounts.member;
+
+        require!(member.is_active, ErrorCode::MemberNotActive);
+        let (Some(required_mint), Some(min_amount), GateMode::Hold) =
+            (channel.required_token_mint, channel.min_token_amount, channel.gate_mode)
+        else {
+            return err!(ErrorCode::NotHoldGatedChannel);
+        };
+        // The owner's seat is never revoked, or the channel could be left ownerless
+        require!(member.wallet != channel.owner, ErrorCode::MemberStillEligible);
+
+        let info = ctx.accounts.member_token_account.to_account_info();
+        let is_token_account = *info.owner == anchor_spl::token::ID
+            || *info.owner == anchor_spl::token_2022::ID;
+        let balance = if is_token_account {
+            TokenAccount::try_deserialize(&mut &info.try_borrow_data()?[..])
+                .ok()
+                .filter(|account| account.owner == member.wallet && account.mint == required_mint)
+                .map_or(0, |account| account.amount)
+        } else {
+            0
+        };
+        require!(balance < min_amount, ErrorCode::MemberStillEligible);
+
+        member.is_active = false;
+        member.role = MemberRole::Member;
+        member.can_post = false;
+        channel.member_count = channel.member_count.saturating_sub(1);
+
+        msg!("Member removed after revalidation: {}", member.wallet);
+        msg!("Remaining members: {}", channel.member_count);
+
+        Ok(())
+    }
+
+    /// Require joining wallets to hold an NFT from a verified Metaplex collection
+    /// (admin or higher). Pass None to remove the requirement
+    /// Applies in addition to any fungible token gate
+    pub fn set_collection_gate(
+        ctx: Context<SetCollectionGate>,
+        required_collection: Option<Pubkey>,
+    ) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+
+        channel.required_collection = required_collection;
+
+        match required_collection {
+            Some(collection) => msg!("Collection gate set: {}", collection),
+            None => msg!("Collection gate removed"),
+        }
+
+        Ok(())
+    }
+
+    /// Set the minimum stake lock and early-exit penalty (admin or higher)
+    /// Penalties and slashes are paid to the treasury, so only the owner can change it
+    /// Members leaving before min_lock_seconds forfeit early_exit_penalty_bps of
+    /// their stake, paid to the treasury token account or left in the vault
+    /// Each stake keeps the lock and penalty in force when it was made
+    pub fn set_stake_policy(
+        ctx: Context<SetStakePolicy>,
+        min_lock_seconds: u32,
+        early_exit_penalty_bps: u16,
+        treasury: Option<Pubkey>,
+    ) -> Result<()> {
+        require!(
+            early_exit_penalty_bps <= MAX_PENALTY_BPS,
+            ErrorCode::InvalidPenalty
+        );
+
+        let channel = &mut ctx.accounts.channel;
+
+        if treasury != channel.treasury {
+            require_keys_eq!(
+                ctx.accounts.authority.key(),
+                channel.owner,
+                ErrorCode::NotChannelOwner
+            );
+        }
+
+        channel.min_lock_seconds = min_lock_seconds;
+        channel.early_exit_penalty_bps = early_exit_penalty_bps;
+        channel.treasury = treasury;
+
+        msg!(
+            "Stake policy set: {}s lock, {} bps early-exit penalty",
+            min_lock_seconds,
+            early_exit_penalty_bps
+        );
+        if let Some(treasury) = treasury {
+            msg!("Treasury: {}", treasury);
+        }
+
+        Ok(())
+    }
+
+    /// Initialize token vault for staking (admin or higher, after set_token_gate)
+    /// Creates a vault PDA and associated token account to hold staked tokens
+    pub fn initialize_vault(ctx: Context<InitializeVault>) -> Result<()> {
+        let channel = &ctx.accounts.channel;
+        let vault = &mut ctx.accounts.token_vault;
+
+        require!(
+            channel.required_token_mint.is_some(),
+            ErrorCode::NotTokenGatedChannel
+        );
+
+        vault.channel = channel.key();
+        vault.token_mint = channel.required_token_mint.unwrap();
+        vault.total_locked = 0;
+        vault.bump = ctx.bumps.token_vault;
+        vault.auth_bump = ctx.bumps.vault_authority;
+        vault.reward_per_share = 0;
+        vault.reward_balance = 0;
+        vault.reward_unreleased = 0;
+        vault.reward_end = 0;
+        vault.last_reward_update = 0;
+        vault.rewards_owed = 0;
+
+        msg!("Token vault initialized for channel: {}", channel.key());
+        msg!("Vault token account: {}", ctx.accounts.vault_token_account.key());
+
+        Ok(())
+    }
+
+    /// Deposit reward tokens for the channel's stakers (channel owner only)
+    /// Rewards are in the gate mint and released linearly over duration_seconds,
+    /// split by locked_amount among whoever is staked as they are released, so
+    /// staking right before a deposit only earns from then on
+    pub fn fund_rewards(
+        ctx: Context<FundRewards>,
+        amount: u64,
+        duration_seconds: u32,
+    ) -> Result<()> {
+        require!(amount > 0, ErrorCode::InvalidRewardAmount);
+        require!(duration_seconds > 0, ErrorCode::InvalidRewardDuration);
+
+        let vault = &mut ctx.accounts.token_vault;
+        let vault_token_account = &mut ctx.accounts.vault_token_account;
+        let token_mint = &ctx.accounts.token_mint;
+
+        require!(vault.total_locked > 0, ErrorCode::NoStakers);
+        require_keys_eq!(token_mint.key(), vault.token_mint, ErrorCode::TokenMintMismatch);
+        require!(
+            vault_token_account.owner == vault.authority()?
+                && vault_token_account.mint == vault.token_mint,
+            ErrorCode::InvalidVaultAccount
+        );
+
+        let balance_before = vault_token_account.amount;
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.owner_token_account.to_account_info(),
+                mint: token_mint.to_account_info(),
+                to: vault_token_account.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info(),
+            },
+        );
+        transfer_checked(transfer_ctx, amount, token_mint.decimals)?;
+
+        // Transfer-fee mints deliver less than was sent, so stream what arrived
+        vault_token_account.reload()?;
+        let received = vault_token_account.amount
+            .checked_sub(balance_before)
+            .ok_or(ErrorCode::Overflow)?;
+
+        let now = Clock::get()?.unix_timestamp;
+        vault.stream_rewards(received, duration_seconds, now)?;
+
+        emit!(RewardsFunded {
+            channel: vault.channel,
+            amount: received,
+            total_locked: vault.total_locked,
+            release_ends_at: vault.reward_end,
+            timestamp: now,
+        });
+
+        msg!("Funded {} reward tokens", received);
+
+        Ok(())
+    }
+
+    /// Pay out a stake's accrued rewards
+    /// Also works after leaving or being removed, as long as the stake record exists
+    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
+        let claimed = pay_rewards(
+            &mut ctx.accounts.member_stake,
+            Some(&mut ctx.accounts.token_vault),
+            Some(&ctx.accounts.vault_authority),
+            Some(&ctx.accounts.vault_token_account),
+            Some(&ctx.accounts.user_token_account),
+            Some(&ctx.accounts.token_mint),
+            Some(&ctx.accounts.token_program),
+        )?;
+        require!(claimed > 0, ErrorCode::NoRewardsToClaim);
+
+        msg!("Claimed {} reward tokens", claimed);
+
+        Ok(())
+    }
+}
+
+// ==================== HELPERS ====================
+
+/// Verify the channel's token gate and, in Stake mode, transfer the stake into the vault
+/// Returns the amount the vault actually received (less any Token-2022
+/// transfer fee), or None if nothing was staked (not token-gated, or Hold mode)
+fn stake_for_membership<'info>(
+    channel: &Account<'info, Channel>,
+    member_wallet: &Signer<'info>,
+    user_token_account: Option<&InterfaceAccount<'info, TokenAccount>>,
+    vault_token_account: Option<&mut InterfaceAccount<'info, TokenAccount>>,
+    token_vault: Option<&mut Account<'info, TokenVault>>,
+    token_mint: Option<&InterfaceAccount<'info, Mint>>,
+    token_program: Option<&Interface<'info, TokenInterface>>,
+) -> Result<Option<u64>> {
+    let (Some(required_mint), Some(min_amount)) =
+        (channel.required_token_mint, channel.min_token_amount)
+    else {
+        return Ok(None);
+    };
+
+    // Token account is required for token-gated channels
+    let user_token_account = user_token_account.ok_or(ErrorCode::TokenAccountRequired)?;
+
+    // Verify token account belongs to the joining wallet
+    require!(
+        user_token_account.owner == member_wallet.key(),
+        ErrorCode::TokenAccountOwnerMismatch
+    );
+
+    // Verify token account is for the correct mint
+    require!(
+        user_token_account.mint == required_mint,
+        ErrorCode::TokenMintMismatch
+    );
+
+    // Verify sufficient balance
+    require!(
+        user_token_account.amount >= min_amount,
+        ErrorCode::InsufficientTokens
+    );
+
+    // Hold-to-join only needs the balance, tokens stay with the member
+    if channel.gate_mode == GateMode::Hold {
+        return Ok(None);
+    }
+
+    // Get vault accounts for token transfer
+    let vault_token_account = vault_token_account.ok_or(ErrorCode::TokenAccountRequired)?;
+    let vault = token_vault.ok_or(ErrorCode::TokenAccountRequired)?;
+    let token_mint = token_mint.ok_or(ErrorCode::TokenAccountRequired)?;
+    let token_program = token_program.ok_or(ErrorCode::TokenAccountRequired)?;
+
+    require_keys_eq!(token_mint.key(), required_mint, ErrorCode::TokenMintMismatch);
+
+    // Stake must land in a token account the vault authority controls,
+    // otherwise total_locked would count tokens the vault never received
+    require!(
+        vault_token_account.owner == vault.authority()?
+            && vault_token_account.mint == vault.token_mint,
+        ErrorCode::InvalidVaultAccount
+    );
+
+    // Transfer tokens from user to vault (user signs, so no PDA signer needed)
+    let balance_before = vault_token_account.amount;
+    let transfer_ctx = CpiContext::new(
+        token_program.to_account_info(),
+        TransferChecked {
+            from: user_token_account.to_account_info(),
+            mint: token_mint.to_account_info(),
+            to: vault_token_account.to_account_info(),
+            authority: member_wallet.to_account_info(),
+        },
+    );
+    transfer_checked(transfer_ctx, min_amount, token_mint.decimals)?;
+
+    // Transfer-fee mints deliver less than was sent, so lock what actually arrived
+    vault_token_account.reload()?;
+    let received = vault_token_account.amount
+        .checked_sub(balance_before)
+        .ok_or(ErrorCode::Overflow)?;
+
+    // Release what was due to the existing stakes before this one dilutes them
+    vault.accrue_rewards(Clock::get()?.unix_timestamp)?;
+
+    // Update vault state
+    vault.total_locked = vault.total_locked
+        .checked_add(received)
+        .ok_or(ErrorCode::Overflow)?;
+
+    Ok(Some(received))
+}
+
+/// Verify the wallet holds an NFT whose Metaplex metadata names the channel's
+/// required collection as verified. No-op if the channel is not collection-gated
+fn verify_collection_nft<'info>(
+    channel: &Account<'info, Channel>,
+    member_wallet: &Signer<'info>,
+    nft_token_account: Option<&InterfaceAccount<'info, TokenAccount>>,
+    nft_metadata: Option<&UncheckedAccount<'info>>,
+) -> Result<()> {
+    let Some(required_collection) = channel.required_collection else {
+        return Ok(());
+    };
+
+    let nft_token_account = nft_token_account.ok_or(ErrorCode::CollectionNftRequired)?;
+    let nft_metadata = nft_metadata.ok_or(ErrorCode::CollectionNftRequired)?;
+
+    require!(
+        nft_token_account.owner == member_wallet.key(),
+        ErrorCode::TokenAccountOwnerMismatch
+    );
+    require!(nft_token_account.amount >= 1, ErrorCode::CollectionNftRequired);
+
+    // Metadata must be the canonical PDA for this mint under the metadata program
+    require_keys_eq!(
+        *nft_metadata.owner,
+        TOKEN_METADATA_PROGRAM_ID,
+        ErrorCode::InvalidNftMetadata
+    );
+    let (metadata_address, _) = Pubkey::find_program_address(
+        &[
+            METADATA_SEED,
+            TOKEN_METADATA_PROGRAM_ID.as_ref(),
+            nft_token_account.mint.as_ref(),
+        ],
+        &TOKEN_METADATA_PROGRAM_ID,
+    );
+    require_keys_eq!(nft_metadata.key(), metadata_address, ErrorCode::InvalidNftMetadata);
+
+    let collection = metadata_collection(&nft_metadata.try_borrow_data()?)
+        .ok_or(ErrorCode::InvalidNftMetadata)?;
+    require!(
+        collection == Some((true, required_collection)),
+        ErrorCode::CollectionMismatch
+    );
+
+    Ok(())
+}
+
+/// Read the `collection` field of a Metaplex MetadataV1 account as (verified, key)
+/// Walks the borsh layout up to that field; returns None if the data is malformed
+fn metadata_collection(data: &[u8]) -> Option<Option<(bool, Pubkey)>> {
+    const METADATA_V1_KEY: u8 = 4;
+
+    let mut cursor = data;
+    let key = u8::deserialize(&mut cursor).ok()?;
+    if key != METADATA_V1_KEY {
+        return None;
+    }
+    // update_authority, mint
+    <(Pubkey, Pubkey)>::deserialize(&mut cursor).ok()?;
+    // name, symbol, uri, seller_fee_basis_points, creators
+    <(String, String, String, u16)>::deserialize(&mut cursor).ok()?;
+    Option::<Vec<(Pubkey, bool, u8)>>::deserialize(&mut cursor).ok()?;
+    // primary_sale_happened, is_mutable, edition_nonce, token_standard
+    <(bool, bool, Option<u8>, Option<u8>)>::deserialize(&mut cursor).ok()?;
+    // collection: (verified, key)
+    Option::<(bool, Pubkey)>::deserialize(&mut cursor).ok()
+}
+
+/// Return a member's locked stake from the vault (vault authority signs as PDA)
+/// Returns the amount released, zero if nothing was locked
+fn release_stake<'info>(
+    stake: &mut MemberStake,
+    token_vault: Option<&mut Account<'info, TokenVault>>,
+    vault_authority: Option<&UncheckedAccount<'info>>,
+    vault_token_account: Option<&InterfaceAccount<'info, TokenAccount>>,
+    destination: Option<&InterfaceAccount<'info, TokenAccount>>,
+    token_mint: Option<&InterfaceAccount<'info, Mint>>,
+    token_program: Option<&Interface<'info, TokenInterface>>,
+) -> Result<u64> {
+    if stake.locked_amount == 0 {
+        return Ok(0);
+    }
+
+    let vault = token_vault.ok_or(ErrorCode::TokenAccountRequired)?;
+    stake.settle_rewards(vault)?;
+    let locked_amount = stake.locked_amount;
+
+    // Transfer tokens back to the member. Any transfer fee comes out of what
+    // the member receives, so exactly locked_amount leaves the vault
+    transfer_from_vault(
+        vault,
+        vault_authority,
+        vault_token_account,
+        destination,
+        token_mint,
+        token_program,
+        locked_amount,
+    )?;
+
+    // Update vault state
+    vault.total_locked = vault.total_locked.saturating_sub(locked_amount);
+
+    // Clear stake record
+    stake.locked_amount = 0;
+
+    Ok(locked_amount)
+}
+
+/// Read a stake record passed unchecked, as leave_channel and kick_member do
+fn load_stake(info: &AccountInfo) -> Result<MemberStake> {
+    require_keys_eq!(*info.owner, crate::ID, ErrorCode::InvalidStakeAccount);
+    MemberStake::try_deserialize(&mut &info.try_borrow_data()?[..])
+}
+
+/// Write back a stake record read with load_stake
+fn store_stake(info: &AccountInfo, stake: &MemberStake) -> Result<()> {
+    stake.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])
+}
+
+/// Settle a stake's rewards and pay out everything it has accrued
+/// Returns the amount paid; without the vault only an empty balance is accepted
+fn pay_rewards<'info>(
+    stake: &mut MemberStake,
+    token_vault: Option<&mut Account<'info, TokenVault>>,
+    vault_authority: Option<&UncheckedAccount<'info>>,
+    vault_token_account: Option<&InterfaceAccount<'info, TokenAccount>>,
+    destination: Option<&InterfaceAccount<'info, TokenAccount>>,
+    token_mint: Option<&InterfaceAccount<'info, Mint>>,
+    token_program: Option<&Interface<'info, TokenInterface>>,
+) -> Result<u64> {
+    let Some(vault) = token_vault else {
+        require!(stake.pending_rewards == 0, ErrorCode::TokenAccountRequired);
+        return Ok(0);
+    };
+
+    stake.settle_rewards(vault)?;
+    let rewards = stake.pending_rewards;
+    if rewards == 0 {
+        return Ok(0);
+    }
+
+    transfer_from_vault(
+        vault,
+        vault_authority,
+        vault_token_account,
+        destination,
+        token_mint,
+        token_program,
+        rewards,
+    )?;
+
+    vault.reward_balance = vault.reward_balance.saturating_sub(rewards);
+    vault.rewards_owed = vault.rewards_owed.saturating_sub(rewards);
+    stake.pending_rewards = 0;
+
+    emit!(RewardsClaimed {
+        channel: stake.channel,
+        member: stake.member,
+        amount: rewards,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+
+    Ok(rewards)
+}
+
+/// Send tokens out of a channel vault, signed by the vault authority PDA
+/// Leaves total_locked to the caller, since not every payout is stake
+fn transfer_from_vault<'info>(
+    vault: &TokenVault,
+    vault_authority: Option<&UncheckedAccount<'info>>,
+    vault_token_account: Option<&InterfaceAccount<'info, TokenAccount>>,
+    destination: Option<&InterfaceAccount<'info, TokenAccount>>,
+    token_mint: Option<&InterfaceAccount<'info, Mint>>,
+    token_program: Option<&Interface<'info, TokenInterface>>,
+    amount: u64,
+) -> Result<()> {
+    let vault_token_account = vault_token_account.ok_or(ErrorCode::TokenAccountRequired)?;
+    let destination = destination.ok_or(ErrorCode::TokenAccountRequired)?;
+    let vault_authority = vault_authority.ok_or(ErrorCode::TokenAccountRequired)?;
+    let token_mint = token_mint.ok_or(ErrorCode::TokenAccountRequired)?;
+    let token_program = token_program.ok_or(ErrorCode::TokenAccountRequired)?;
+
+    require_keys_eq!(token_mint.key(), vault.token_mint, ErrorCode::TokenMintMismatch);
+
+    // PDA signer seeds for vault authority
+    let seeds = &[
+        VAULT_AUTH_SEED,
+        vault.channel.as_ref(),
+        vault.token_mint.as_ref(),
+        &[vault.auth_bump],
+    ];
+    let signer_seeds = &[&seeds[..]];
+
+    let transfer_ctx = CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        TransferChecked {
+            from: vault_token_account.to_account_info(),
+            mint: token_mint.to_account_info(),
+            to: destination.to_account_info(),
+            authority: vault_authority.to_account_info(),
+        },
+        signer_seeds,
+    );
+    transfer_checked(transfer_ctx, amount, token_mint.decimals)
+}
+
+/// Reject posts that come sooner than the channel's slow mode interval
+/// Moderators and above are exempt. Records the post time on the member
+fn enforce_slow_mode(channel: &Channel, member: &mut Member, now: i64) -> Result<()> {
+    let exempt = channel.has_role(&member.wallet, Some(member), MemberRole::Moderator);
+
+    if channel.slow_mode_seconds > 0 && !exempt {
+        require!(
+            now >= member.last_message_at.saturating_add(channel.slow_mode_seconds as i64),
+            ErrorCode::SlowModeActive
+        );
+    }
+
+    member.last_message_at = now;
+
+    Ok(())
+}
+
+/// Assign the next message number and emit MessageLogged for it
+/// Returns the new message number
+fn append_message(
+    channel: &mut Account<Channel>,
+    sender: Pubkey,
+    message_hash: [u8; 32],
+    encrypted_ipfs_cid: Vec<u8>,
+    reply_to: Option<u64>,
+    thread_root: Option<u64>,
+    timestamp: i64,
+) -> Result<u64> {
+    channel.message_count = channel.message_count
+        .checked_add(1)
+        .ok_or(ErrorCode::Overflow)?;
+    channel.message_digest = Channel::next_digest(
+        &channel.message_digest,
+        channel.message_count,
+        &sender,
+        &message_hash,
+        timestamp,
+    );
+
+    // First message after the mark was used up starts a new retention mark
+    if channel.retention_seconds > 0 && channel.retention_mark_at == 0 {
+        channel.retention_mark = channel.message_count;
+        channel.retention_mark_at = timestamp;
+    }
+
+    // Emit event for Helius monitoring
+    emit!(MessageLogged {
+        channel: channel.key(),
+        sender,
+        message_hash,
+        encrypted_ipfs_cid,
+        message_number: channel.message_count,
+        reply_to,
+        thread_root,
+        key_epoch: channel.key_epoch,
+        digest: channel.message_digest,
+        retention_seconds: channel.retention_seconds,
+        timestamp,
+    });
+
+    Ok(channel.message_count)
+}
+
+/// Create a KeyEnvelope at the channel's current epoch for each
+/// (member, envelope) pair in `accounts`
+fn write_key_envelopes<'info>(
+    channel: &Account<'info, Channel>,
+    accounts: &'info [AccountInfo<'info>],
+    wrapped_keys: Vec<WrappedKey>,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+) -> Result<()> {
+    require!(
+        accounts.len() == wrapped_keys.len() * 2,
+        ErrorCode::InvalidKeyEnvelope
+    );
+
+    let channel_key = channel.key();
+    let created_bytes = channel.created_at.to_le_bytes();
+    let epoch_bytes = channel.key_epoch.to_le_bytes();
+    let now = Clock::get()?.unix_timestamp;
+
+    for (pair, wrapped) in accounts.chunks(2).zip(wrapped_keys) {
+        let member = Account::<Member>::try_from(&pair[0])?;
+        require!(
+            member.channel == channel_key && member.is_active,
+            ErrorCode::InvalidKeyEnvelope
+        );
+        require!(
+            !wrapped.wrapped_key.is_empty() && wrapped.wrapped_key.len() <= MAX_WRAPPED_KEY_SIZE,
+            ErrorCode::WrappedKeyTooLarge
+        );
+
+        let envelope_info = &pair[1];
+        let (address, bump) = Pubkey::find_program_address(
+            &[KEY_ENVELOPE_SEED, channel_key.as_ref(), &created_bytes, member.wallet.as_ref(), &epoch_bytes],
+            &crate::ID,
+        );
+        require_keys_eq!(envelope_info.key(), address, ErrorCode::InvalidKeyEnvelope);
+
+        create_program_account(
+            envelope_info,
+            payer,
+            system_program,
+            8 + KeyEnvelope::LEN,
+            &[KEY_ENVELOPE_SEED, channel_key.as_ref(), &created_bytes, member.wallet.as_ref(), &epoch_bytes, &[bump]],
+        )?;
+
+        let envelope = KeyEnvelope {
+            channel: channel_key,
+            wallet: member.wallet,
+            key_epoch: channel.key_epoch,
+            key_version: wrapped.key_version,
+            wrapped_key: wrapped.wrapped_key,
+            created_at: now,
+            bump,
+            payer: payer.key(),
+        };
+        envelope.try_serialize(&mut &mut envelope_info.try_borrow_mut_data()?[..])?;
+    }
+
+    Ok(())
+}
+
+/// Create a PDA owned by this program that is not held as a typed Account
+/// Like Anchor's init, lamports sent to the address beforehand are kept
+fn create_program_account<'info>(
+    info: &AccountInfo<'info>,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    space: usize,
+    seeds: &[&[u8]],
+) -> Result<()> {
+    require!(
+        info.owner == &System::id() && info.data_is_empty(),
+        ErrorCode::InvalidKeyEnvelope
+    );
+
+    let rent = Rent::get()?.minimum_balance(space);
+    let signer_seeds = &[seeds];
+
+    if info.lamports() == 0 {
+        system_program::create_account(
+            CpiContext::new_with_signer(
+                system_program.to_account_info(),
+                system_program::CreateAccount {
+                    from: payer.to_account_info(),
+                    to: info.clone(),
+                },
+                signer_seeds,
+            ),
+            rent,
+            space as u64,
+            &crate::ID,
+        )?;
+        return Ok(());
+    }
+
+    let top_up = rent.saturating_sub(info.lamports());
+    if top_up > 0 {
+        system_program::transfer(
+            CpiContext::new(
+                system_program.to_account_info(),
+                system_program::Transfer {
+                    from: payer.to_account_info(),
+                    to: info.clone(),
+                },
+            ),
+            top_up,
+        )?;
+    }
+    system_program::allocate(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            system_program::Allocate {
+                account_to_allocate: info.clone(),
+            },
+            signer_seeds,
+        ),
+        space as u64,
+    )?;
+    system_program::assign(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            system_program::Assign {
+                account_to_assign: info.clone(),
+            },
+            signer_seeds,
+        ),
+        &crate::ID,
+    )?;
+
+    Ok(())
+}
+
+/// Grow an account created under an older, shorter layout to new_len,
+/// topping its rent up from the payer. New trailing bytes are zeroed
+fn grow_account<'info>(
+    info: &AccountInfo<'info>,
+    new_len: usize,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+) -> Result<()> {
+    if info.data_len() >= new_len {
+        return Ok(());
+    }
+
+    let required = Rent::get()?.minimum_balance(new_len);
+    let top_up = required.saturating_sub(info.lamports());
+    if top_up > 0 {
+        system_program::transfer(
+            CpiContext::new(
+                system_program.to_account_info(),
+                system_program::Transfer {
+                    from: payer.to_account_info(),
+                    to: info.clone(),
+                },
+            ),
+            top_up,
+        )?;
+    }
+    info.resize(new_len)?;
+
+    Ok(())
+}
+
+/// Close an account owned by this program that is not held as a typed Account
+fn close_program_account<'info>(
+    info: &AccountInfo<'info>,
+    destination: &AccountInfo<'info>,
+) -> Result<()> {
+    let lamports = info.lamports();
+    **destination.try_borrow_mut_lamports()? = destination
+        .lamports()
+        .checked_add(lamports)
+        .ok_or(ErrorCode::Overflow)?;
+    **info.try_borrow_mut_lamports()? = 0;
+
+    info.assign(&System::id());
+    info.resize(0)?;
+
+    Ok(())
+}
+
+// ==================== ACCOUNTS ====================
+
+#[derive(Accounts)]
+#[instruction(channel_id: u64, encrypted_metadata: Vec<u8>)]
+pub struct CreateChannel<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = Channel::space(encrypted_metadata.len()),
+        seeds = [CHANNEL_SEED, owner.key().as_ref(), channel_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + Member::LEN,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub member: Account<'info, Member>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    /// Owner's published encryption key, recorded on the member (optional)
+    #[account(
+        seeds = [USER_KEY_SEED, owner.key().as_ref()],
+        bump = user_key.bump
+    )]
+    pub user_key: Option<Account<'info, UserKey>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(channel_id: u64, encrypted_metadata: Vec<u8>)]
+pub struct CreateChannelAndJoin<'info> {
+    #[account(
+        init,
+        payer = creator,
+        space = Channel::space(encrypted_metadata.len()),
+        seeds = [CHANNEL_SEED, creator.key().as_ref(), channel_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + Member::LEN,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), creator.key().as_ref()],
+        bump
+    )]
+    pub member: Account<'info, Member>,
+
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    /// Creator's published encryption key, recorded on the member (optional)
+    #[account(
+        seeds = [USER_KEY_SEED, creator.key().as_ref()],
+        bump = user_key.bump
+    )]
+    pub user_key: Option<Account<'info, UserKey>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(encrypted_metadata: Vec<u8>)]
+pub struct CreateDirectMessage<'info> {
+    #[account(
+        init,
+        payer = creator,
+        space = Channel::space(encrypted_metadata.len()),
+        seeds = [
+            DM_SEED,
+            creator.key().min(peer.key()).as_ref(),
+            creator.key().max(peer.key()).as_ref()
+        ],
+        bump
+    )]
+    pub channel: Account<'info, Channel>,
+
+    /// Created, or reactivated if left over from a closed incarnation of this DM
+    #[account(
+        init_if_needed,
+        payer = creator,
+        space = 8 + Member::LEN,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), creator.key().as_ref()],
+        bump,
+        constraint = !creator_member.is_active @ ErrorCode::MemberAlreadyActive
+    )]
+    pub creator_member: Account<'info, Member>,
+
+    /// Created, or reactivated if left over from a closed incarnation of this DM
+    #[account(
+        init_if_needed,
+        payer = creator,
+        space = 8 + Member::LEN,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), peer.key().as_ref()],
+        bump,
+        constraint = !peer_member.is_active @ ErrorCode::MemberAlreadyActive
+    )]
+    pub peer_member: Account<'info, Member>,
+
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    /// CHECK: The other participant; only its key is used
+    pub peer: UncheckedAccount<'info>,
+
+    /// Creator's published encryption key, recorded on the member (optional)
+    #[account(
+        seeds = [USER_KEY_SEED, creator.key().as_ref()],
+        bump = creator_key.bump
+    )]
+    pub creator_key: Option<Account<'info, UserKey>>,
+
+    /// Peer's published encryption key, recorded on the member (optional)
+    #[account(
+        seeds = [USER_KEY_SEED, peer.key().as_ref()],
+        bump = peer_key.bump
+    )]
+    pub peer_key: Option<Account<'info, UserKey>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct JoinChannel<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        init,
+        payer = member_wallet,
+        space = 8 + Member::LEN,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), member_wallet.key().as_ref()],
+        bump
+    )]
+    pub member: Account<'info, Member>,
+
+    #[account(mut)]
+    pub member_wallet: Signer<'info>,
+
+    /// Joining wallet's published encryption key, recorded on the member (optional)
+    #[account(
+        seeds = [USER_KEY_SEED, member_wallet.key().as_ref()],
+        bump = user_key.bump
+    )]
+    pub user_key: Option<Account<'info, UserKey>>,
+
+    /// CHECK: Must be empty, a Ban account here means the wallet is banned
+    #[account(
+        seeds = [BAN_SEED, channel.key().as_ref(), &channel.created_at.to_le_bytes(), member_wallet.key().as_ref()],
+        bump,
+        constraint = ban_record.data_is_empty() @ ErrorCode::MemberBanned
+    )]
+    pub ban_record: UncheckedAccount<'info>,
+
+    /// Token account holding the wallet's collection NFT (required for collection-gated channels)
+    pub nft_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// CHECK: Metaplex metadata of the NFT, verified in verify_collection_nft
+    pub nft_metadata: Option<UncheckedAccount<'info>>,
+
+    /// User's token account (required for token-gated channels)
+    #[account(mut)]
+    pub user_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Token vault account (required for token-gated channels)
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, channel.key().as_ref()],
+        bump = token_vault.bump
+    )]
+    pub token_vault: Option<Account<'info, TokenVault>>,
+
+    /// Vault's token account to receive staked tokens
+    #[account(mut)]
+    pub vault_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Member stake record (created for token-gated channels)
+    #[account(
+        init,
+        payer = member_wallet,
+        space = 8 + MemberStake::LEN,
+        seeds = [STAKE_SEED, channel.key().as_ref(), member_wallet.key().as_ref()],
+        bump
+    )]
+    pub member_stake: Option<Account<'info, MemberStake>>,
+
+    /// Gate mint, needed for transfer_checked (required for token-gated channels)
+    pub token_mint: Option<InterfaceAccount<'info, Mint>>,
+
+    /// Token program, SPL Token or Token-2022 (required for token-gated channels)
+    pub token_program: Option<Interface<'info, TokenInterface>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> JoinChannel<'info> {
+    /// Shared join path for join_channel and join_with_invite
+    fn join(&mut self, bumps: &JoinChannelBumps) -> Result<()> {
+        require!(self.channel.is_active, ErrorCode::ChannelInactive);
+        require!(
+            self.channel.channel_type != ChannelType::DirectMessage,
+            ErrorCode::DirectMessageFull
+        );
+        require!(
+            self.channel.member_count < self.channel.member_limit(),
+            ErrorCode::ChannelFull
+        );
+
+        verify_collection_nft(
+            &self.channel,
+            &self.member_wallet,
+            self.nft_token_account.as_ref(),
+            self.nft_metadata.as_ref(),
+        )?;
+
+        // Token-gating with staking (if channel requires it)
+        if let Some(staked) = stake_for_membership(
+            &self.channel,
+            &self.member_wallet,
+            self.user_token_account.as_ref(),
+            self.vault_token_account.as_mut(),
+            self.token_vault.as_mut(),
+            self.token_mint.as_ref(),
+            self.token_program.as_ref(),
+        )? {
+            let stake = self.member_stake
+                .as_mut()
+                .ok_or(ErrorCode::TokenAccountRequired)?;
+
+            // Rewards released before this stake do not accrue to it
+            let vault = self.token_vault
+                .as_mut()
+                .ok_or(ErrorCode::TokenAccountRequired)?;
+            stake.settle_rewards(vault)?;
+
+            // Initialize stake record
+            stake.member = self.member_wallet.key();
+            stake.channel = self.channel.key();
+            stake.lock(&self.channel, staked, Clock::get()?.unix_timestamp);
+            stake.bump = bumps.member_stake.ok_or(ErrorCode::TokenAccountRequired)?;
+
+            msg!("Staked {} tokens to vault", staked);
+        }
+
+        let clock = Clock::get()?;
+        let channel = &mut self.channel;
+        let member_account = &mut self.member;
+
+        member_account.channel = channel.key();
+        member_account.wallet = self.member_wallet.key();
+        member_account.joined_at = clock.unix_timestamp;
+        member_account.is_active = true;
+        member_account.role = if member_account.wallet == channel.owner {
+            MemberRole::Owner
+        } else {
+            MemberRole::Member
+        };
+        member_account.key_version = UserKey::version_of(self.user_key.as_deref());
+        member_account.gate_token_account = self.user_token_account
+            .as_ref()
+            .map_or(Pubkey::default(), |account| account.key());
+        member_account.bump = bumps.member;
+
+        channel.member_count += 1;
+
+        msg!("Member joined: {}", member_account.wallet);
+        msg!("Total members: {}", channel.member_count);
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct JoinWithInvite<'info> {
+    pub join: JoinChannel<'info>,
+
+    #[account(
+        mut,
+        seeds = [INVITE_SEED, join.channel.key().as_ref(), &join.channel.created_at.to_le_bytes(), invite.invite_key.as_ref()],
+        bump = invite.bump,
+        constraint = invite.invite_key == invite_signer.key() @ ErrorCode::InvalidInviteSigner
+    )]
+    pub invite: Account<'info, Invite>,
+
+    /// Invite keypair, proves the joiner was handed the invite
+    pub invite_signer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(invite_key: Pubkey)]
+pub struct CreateInvite<'info> {
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Invite::LEN,
+        seeds = [INVITE_SEED, channel.key().as_ref(), &channel.created_at.to_le_bytes(), invite_key.as_ref()],
+        bump
+    )]
+    pub invite: Account<'info, Invite>,
+
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    #[account(
+        mut,
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Admin)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RevokeInvite<'info> {
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        close = creator,
+        seeds = [INVITE_SEED, channel.key().as_ref(), &channel.created_at.to_le_bytes(), invite.invite_key.as_ref()],
+        bump = invite.bump
+    )]
+    pub invite: Account<'info, Invite>,
+
+    /// CHECK: Rent refund destination, must be the original invite creator
+    #[account(mut, address = invite.creator)]
+    pub creator: UncheckedAccount<'info>,
+
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    #[account(
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Admin)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct LogMessage<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        constraint = member.channel == channel.key() @ ErrorCode::NotChannelMember,
+        constraint = member.wallet == sender.key() @ ErrorCode::UnauthorizedSender
+    )]
+    pub member: Account<'info, Member>,
+
+    /// Sender record for the new message, needed to edit or delete it later
+    #[account(
+        init,
+        payer = sender,
+        space = 8 + MessageRecord::LEN,
+        seeds = [MESSAGE_SEED, channel.key().as_ref(), &channel.created_at.to_le_bytes(), &(channel.message_count + 1).to_le_bytes()],
+        bump
+    )]
+    pub message_record: Option<Account<'info, MessageRecord>>,
+
+    #[account(mut)]
+    pub sender: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct LogMessages<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        constraint = member.channel == channel.key() @ ErrorCode::NotChannelMember,
+        constraint = member.wallet == sender.key() @ ErrorCode::UnauthorizedSender
+    )]
+    pub member: Account<'info, Member>,
+
+    pub sender: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireMessages<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, Channel>,
+}
+
+#[derive(Accounts)]
+pub struct ReactToMessage<'info> {
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        constraint = member.channel == channel.key() @ ErrorCode::NotChannelMember,
+        constraint = member.wallet == sender.key() @ ErrorCode::UnauthorizedSender
+    )]
+    pub member: Account<'info, Member>,
+
+    pub sender: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct PinMessage<'info> {
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + ChannelPins::LEN,
+        seeds = [PINS_SEED, channel.key().as_ref(), &channel.created_at.to_le_bytes()],
+        bump
+    )]
+    pub pins: Account<'info, ChannelPins>,
+
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    #[account(
+        mut,
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Admin)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnpinMessage<'info> {
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        seeds = [PINS_SEED, channel.key().as_ref(), &channel.created_at.to_le_bytes()],
+        bump = pins.bump
+    )]
+    pub pins: Account<'info, ChannelPins>,
+
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    #[account(
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Admin)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct EditMessage<'info> {
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        seeds = [MESSAGE_SEED, channel.key().as_ref(), &channel.created_at.to_le_bytes(), &message_record.message_number.to_le_bytes()],
+        bump = message_record.bump,
+        constraint = message_record.sender == sender.key() @ ErrorCode::UnauthorizedSender
+    )]
+    pub message_record: Account<'info, MessageRecord>,
+
+    #[account(
+        constraint = member.channel == channel.key() @ ErrorCode::NotChannelMember,
+        constraint = member.wallet == sender.key() @ ErrorCode::UnauthorizedSender
+    )]
+    pub member: Account<'info, Member>,
+
+    pub sender: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteMessage<'info> {
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        close = sender,
+        seeds = [MESSAGE_SEED, channel.key().as_ref(), &channel.created_at.to_le_bytes(), &message_record.message_number.to_le_bytes()],
+        bump = message_record.bump
+    )]
+    pub message_record: Account<'info, MessageRecord>,
+
+    /// CHECK: Original sender, receives the record's rent
+    #[account(mut, address = message_record.sender)]
+    pub sender: UncheckedAccount<'info>,
+
+    /// Signer's member record (not needed for the sender or the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    #[account(
+        constraint = authority.key() == message_record.sender
+            || channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Moderator)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(message_number: u64)]
+pub struct RemoveMessage<'info> {
+    pub channel: Account<'info, Channel>,
+
+    /// CHECK: Must be empty, messages with a record are retracted with delete_message
+    #[account(
+        seeds = [MESSAGE_SEED, channel.key().as_ref(), &channel.created_at.to_le_bytes(), &message_number.to_le_bytes()],
+        bump,
+        constraint = message_record.data_is_empty() @ ErrorCode::MessageRecordExists
+    )]
+    pub message_record: UncheckedAccount<'info>,
+
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    #[account(
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Moderator)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(new_encrypted_metadata: Option<Vec<u8>>)]
+pub struct UpdateChannel<'info> {
+    /// Resized to fit the new metadata; the owner pays for growth and
+    /// receives the refund when it shrinks
+    #[account(
+        mut,
+        realloc = Channel::space(
+            new_encrypted_metadata
+                .as_ref()
+                .map_or(channel.encrypted_metadata.len(), |metadata| metadata.len())
+        ),
+        realloc::payer = owner,
+        realloc::zero = false
+    )]
+    pub channel: Account<'info, Channel>,
+
+    /// CHECK: Channel owner, only moves rent; must also sign if the account grows
+    #[account(mut, address = channel.owner)]
+    pub owner: UncheckedAccount<'info>,
+
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    #[account(
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Admin)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateChannel<'info> {
+    /// CHECK: Deserialized by hand in the handler because older layouts may not
+    /// fit the current Channel struct until the account has been resized
+    #[account(mut, owner = crate::ID)]
+    pub channel: UncheckedAccount<'info>,
+
+    /// CHECK: Member PDA for the channel owner, verified in the handler and created
+    /// there if the owner's seat predates Member accounts
+    #[account(mut)]
+    pub owner_member: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateMember<'info> {
+    /// CHECK: Deserialized by hand in the handler because older layouts do not
+    /// fit the current Member struct until the account has been resized
+    #[account(mut, owner = crate::ID)]
+    pub member: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateVault<'info> {
+    /// CHECK: Deserialized by hand in the handler because older layouts do not
+    /// fit the current TokenVault struct until the account has been resized
+    #[account(mut, owner = crate::ID)]
+    pub token_vault: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateStake<'info> {
+    /// CHECK: Deserialized by hand in the handler because older layouts do not
+    /// fit the current MemberStake struct until the account has been resized
+    #[account(mut, owner = crate::ID)]
+    pub member_stake: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ChangeMemberRole<'info> {
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), member.wallet.as_ref()],
+        bump = member.bump
+    )]
+    pub member: Account<'info, Member>,
+
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetMemberPosting<'info> {
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), member.wallet.as_ref()],
+        bump = member.bump
+    )]
+    pub member: Account<'info, Member>,
+
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    #[account(
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Admin)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct LeaveChannel<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), member_wallet.key().as_ref()],
+        bump = member.bump,
+        constraint = member.wallet == member_wallet.key() @ ErrorCode::UnauthorizedSender
+    )]
+    pub member: Account<'info, Member>,
+
+    pub member_wallet: Signer<'info>,
+
+    /// Token vault account (for returning staked tokens)
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, channel.key().as_ref()],
+        bump = token_vault.bump
+    )]
+    pub token_vault: Option<Account<'info, TokenVault>>,
+
+    /// CHECK: PDA authority for vault token transfers
+    pub vault_authority: Option<UncheckedAccount<'info>>,
+
+    /// Vault's token account (source of returned tokens)
+    #[account(mut)]
+    pub vault_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// User's token account (destination for returned tokens)
+    #[account(mut)]
+    pub user_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// CHECK: Member stake record, empty if the member never staked. Always
+    /// required so leaving cannot skip the reward payout or the stake release
+    #[account(
+        mut,
+        seeds = [STAKE_SEED, channel.key().as_ref(), member_wallet.key().as_ref()],
+        bump
+    )]
+    pub member_stake: UncheckedAccount<'info>,
+
+    /// Channel treasury (destination for early-exit penalties)
+    #[account(
+        mut,
+        constraint = channel.treasury == Some(treasury_token_account.key()) @ ErrorCode::InvalidTreasury
+    )]
+    pub treasury_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Gate mint, needed for transfer_checked
+    pub token_mint: Option<InterfaceAccount<'info, Mint>>,
+
+    /// Token program, SPL Token or Token-2022
+    pub token_program: Option<Interface<'info, TokenInterface>>,
+
+    /// Admin who takes over when the owner leaves a non-empty channel
+    #[account(
+        mut,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), successor.wallet.as_ref()],
+        bump = successor.bump
+    )]
+    pub successor: Option<Account<'info, Member>>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeOwner<'info> {
+    #[account(
+        mut,
+        constraint = channel.owner == owner.key() @ ErrorCode::NotChannelOwner
+    )]
+    pub channel: Account<'info, Channel>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptOwnership<'info> {
+    #[account(
+        mut,
+        constraint = channel.pending_owner == Some(new_owner.key()) @ ErrorCode::NotPendingOwner
+    )]
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), new_owner.key().as_ref()],
+        bump = new_owner_member.bump
+    )]
+    pub new_owner_member: Account<'info, Member>,
+
+    /// CHECK: Previous owner's member record, demoted in the handler if it exists
+    /// Always passed, so the demotion cannot be skipped by leaving it out
+    #[account(
+        mut,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), channel.owner.as_ref()],
+        bump
+    )]
+    pub previous_owner_member: UncheckedAccount<'info>,
+
+    pub new_owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct KickMember<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), member.wallet.as_ref()],
+        bump = member.bump
+    )]
+    pub member: Account<'info, Member>,
+
+    /// Token vault account (for returning staked tokens)
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, channel.key().as_ref()],
+        bump = token_vault.bump
+    )]
+    pub token_vault: Option<Account<'info, TokenVault>>,
+
+    /// CHECK: PDA authority for vault token transfers
+    pub vault_authority: Option<UncheckedAccount<'info>>,
+
+    /// Vault's token account (source of returned tokens)
+    #[account(mut)]
+    pub vault_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Removed member's token account (destination for returned tokens)
+    #[account(
+        mut,
+        constraint = member_token_account.owner == member.wallet @ ErrorCode::TokenAccountOwnerMismatch
+    )]
+    pub member_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// CHECK: Member stake record, empty if the member never staked. Always
+    /// required so removal cannot skip the reward payout or the stake release
+    #[account(
+        mut,
+        seeds = [STAKE_SEED, channel.key().as_ref(), member.wallet.as_ref()],
+        bump
+    )]
+    pub member_stake: UncheckedAccount<'info>,
+
+    /// Gate mint, needed for transfer_checked
+    pub token_mint: Option<InterfaceAccount<'info, Mint>>,
+
+    /// Token program, SPL Token or Token-2022
+    pub token_program: Option<Interface<'info, TokenInterface>>,
+
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    #[account(
+        mut,
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Moderator)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+}
+
+impl<'info> KickMember<'info> {
+    /// Shared removal path for kick_member and ban_member
+    /// The signer must outrank the member; inactive members are left untouched
+    fn remove(&mut self) -> Result<()> {
+        let authority_role = self.channel
+            .role_of(&self.authority.key(), self.authority_member.as_deref())
+            .ok_or(ErrorCode::InsufficientRole)?;
+        require!(authority_role > self.member.role, ErrorCode::InsufficientRole);
+
+        if !self.member.is_active {
+            return Ok(());
+        }
+
+        self.member.is_active = false;
+        self.member.role = MemberRole::Member;
+        self.member.can_post = false;
+        self.channel.member_count = self.channel.member_count.saturating_sub(1);
+
+        // Pay out rewards and return staked tokens if this was a token-gated
+        // channel with staking
+        let stake_info = self.member_stake.to_account_info();
+        if !stake_info.data_is_empty() {
+            let stake = &mut load_stake(&stake_info)?;
+
+            let rewarded = pay_rewards(
+                stake,
+                self.token_vault.as_mut(),
+                self.vault_authority.as_ref(),
+                self.vault_token_account.as_ref(),
+                self.member_token_account.as_ref(),
+                self.token_mint.as_ref(),
+                self.token_program.as_ref(),
+            )?;
+            if rewarded > 0 {
+                msg!("Paid {} reward tokens to removed member", rewarded);
+            }
+
+            let returned = release_stake(
+                stake,
+                self.token_vault.as_mut(),
+                self.vault_authority.as_ref(),
+                self.vault_token_account.as_ref(),
+                self.member_token_account.as_ref(),
+                self.token_mint.as_ref(),
+                self.token_program.as_ref(),
+            )?;
+            if returned > 0 {
+                msg!("Returned {} tokens to removed member", returned);
+            }
+
+            store_stake(&stake_info, stake)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct SlashStake<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        seeds = [MEMBER_SEED, channel.key().as_ref(), member.wallet.as_ref()],
+        bump = member.bump
+    )]
+    pub member: Account<'info, Member>,
+
+    #[account(
+        mut,
+        seeds = [STAKE_SEED, channel.key().as_ref(), member.wallet.as_ref()],
+        bump = member_stake.bump
+    )]
+    pub member_stake: Account<'info, MemberStake>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, channel.key().as_ref()],
+        bump = token_vault.bump
+    )]
+    pub token_vault: Account<'info, TokenVault>,
+
+    /// CHECK: PDA authority for vault token transfers
+    pub vault_authority: UncheckedAccount<'info>,
+
+    /// Vault's token account (source of slashed tokens)
+    #[account(mut)]
+    pub vault_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Channel treasury (destination for slashed tokens)
+    #[account(
+        mut,
+        constraint = channel.treasury == Some(treasury_token_account.key()) @ ErrorCode::InvalidTreasury
+    )]
+    pub treasury_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Gate mint, needed for transfer_checked
+    pub token_mint: InterfaceAccount<'info, Mint>,
+
+    /// Token program, SPL Token or Token-2022
+    pub token_program: Interface<'info, TokenInterface>,
+
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    #[account(
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Admin)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct BanMember<'info> {
+    pub kick: KickMember<'info>,
+
+    #[account(
+        init,
+        payer = kick.authority,
+        space = 8 + Ban::LEN,
+        seeds = [BAN_SEED, kick.channel.key().as_ref(), &kick.channel.created_at.to_le_bytes(), kick.member.wallet.as_ref()],
+        bump
+    )]
+    pub ban: Account<'info, Ban>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnbanMember<'info> {
+    pub channel: Account<'info, Channel>,
+
+    #[account(
+        mut,
+        close = banned_by,
+        seeds = [BAN_SEED, channel.key().as_ref(), &channel.created_at.to_le_bytes(), ban.wallet.as_ref()],
+        bump = ban.bump
+    )]
+    pub ban: Account<'info, Ban>,
+
+    /// CHECK: Rent refund destination, must be whoever created the ban
+    #[account(mut, address = ban.banned_by)]
+    pub banned_by: UncheckedAccount<'info>,
+
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    #[account(
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Moderator)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseChannel<'info> {
+    #[account(
+        mut,
+        close = owner,
+        constraint = channel.owner == owner.key() @ ErrorCode::NotChannelOwner,
+        constraint = channel.member_count == 0 @ ErrorCode::ChannelNotEmpty
+    )]
+    pub channel: Account<'info, Channel>,
+
+    /// CHECK: Vault PDA, inspected and closed in the handler if it was ever initialized
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, channel.key().as_ref()],
+        bump
+    )]
+    pub token_vault: UncheckedAccount<'info>,
+
+    /// CHECK: PDA authority for vault token transfers, verified against the vault
+    pub vault_authority: Option<UncheckedAccount<'info>>,
+
+    /// Vault's token account (closed along with the vault)
+    #[account(mut)]
+    pub vault_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Owner's token account (receives stray tokens left in the vault)
+    #[account(mut)]
+    pub owner_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Gate mint, needed for transfer_checked
+    pub token_mint: Option<InterfaceAccount<'info, Mint>>,
+
+    /// Token program, SPL Token or Token-2022
+    pub token_program: Option<Interface<'info, TokenInterface>>,
+
+    /// Channel pins (closed along with the channel if they were ever created)
+    #[account(
+        mut,
+        close = owner,
+        seeds = [PINS_SEED, channel.key().as_ref(), &channel.created_at.to_le_bytes()],
+        bump = pins.bump
+    )]
+    pub pins: Option<Account<'info, ChannelPins>>,
 
     #[account(mut)]
     pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseMember<'info> {
+    #[account(
+        mut,
+        close = wallet,
+        seeds = [MEMBER_SEED, member.channel.as_ref(), wallet.key().as_ref()],
+        bump = member.bump,
+        constraint = !member.is_active @ ErrorCode::MemberStillActive
+    )]
+    pub member: Account<'info, Member>,
+
+    /// CHECK: Must be empty, the stake record has to be closed before the member
+    #[account(
+        seeds = [STAKE_SEED, member.channel.as_ref(), wallet.key().as_ref()],
+        bump,
+        constraint = member_stake.data_is_empty() @ ErrorCode::StakeNotClosed
+    )]
+    pub member_stake: UncheckedAccount<'info>,
 
-    pub system_program: Program<'info, System>,
+    #[account(mut)]
+    pub wallet: Signer<'info>,
 }
 
 #[derive(Accounts)]
-#[instruction(channel_id: u64)]
-pub struct CreateChannelAndJoin<'info> {
+pub struct CloseStake<'info> {
     #[account(
-        init,
-        payer = creator,
-        space = 8 + Channel::LEN,
-        seeds = [CHANNEL_SEED, creator.key().as_ref(), channel_id.to_le_bytes().as_ref()],
-        bump
+        mut,
+        close = wallet,
+        seeds = [STAKE_SEED, member_stake.channel.as_ref(), wallet.key().as_ref()],
+        bump = member_stake.bump
     )]
-    pub channel: Account<'info, Channel>,
+    pub member_stake: Account<'info, MemberStake>,
 
+    /// Member record, which must have left the channel
     #[account(
-        init,
-        payer = creator,
-        space = 8 + Member::LEN,
-        seeds = [MEMBER_SEED, channel.key().as_ref(), creator.key().as_ref()],
-        bump
+        seeds = [MEMBER_SEED, member_stake.channel.as_ref(), wallet.key().as_ref()],
+        bump = member.bump,
+        constraint = !member.is_active @ ErrorCode::MemberStillActive
     )]
     pub member: Account<'info, Member>,
 
+    /// Token vault account (for returning tokens still locked)
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, member_stake.channel.as_ref()],
+        bump = token_vault.bump
+    )]
+    pub token_vault: Option<Account<'info, TokenVault>>,
+
+    /// CHECK: PDA authority for vault token transfers
+    pub vault_authority: Option<UncheckedAccount<'info>>,
+
+    /// Vault's token account (source of returned tokens)
     #[account(mut)]
-    pub creator: Signer<'info>,
+    pub vault_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
 
-    pub system_program: Program<'info, System>,
+    /// User's token account (destination for returned tokens)
+    #[account(mut)]
+    pub user_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Gate mint, needed for transfer_checked
+    pub token_mint: Option<InterfaceAccount<'info, Mint>>,
+
+    /// Token program, SPL Token or Token-2022
+    pub token_program: Option<Interface<'info, TokenInterface>>,
+
+    #[account(mut)]
+    pub wallet: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct JoinChannel<'info> {
+pub struct RejoinChannel<'info> {
     #[account(mut)]
     pub channel: Account<'info, Channel>,
 
     #[account(
-        init,
-        payer = member_wallet,
-        space = 8 + Member::LEN,
+        mut,
         seeds = [MEMBER_SEED, channel.key().as_ref(), member_wallet.key().as_ref()],
-        bump
+        bump = member.bump,
+        constraint = member.wallet == member_wallet.key() @ ErrorCode::UnauthorizedSender
     )]
     pub member: Account<'info, Member>,
 
     #[account(mut)]
     pub member_wallet: Signer<'info>,
 
+    /// Rejoining wallet's published encryption key, recorded on the member (optional)
+    #[account(
+        seeds = [USER_KEY_SEED, member_wallet.key().as_ref()],
+        bump = user_key.bump
+    )]
+    pub user_key: Option<Account<'info, UserKey>>,
+
+    /// CHECK: Must be empty, a Ban account here means the wallet is banned
+    #[account(
+        seeds = [BAN_SEED, channel.key().as_ref(), &channel.created_at.to_le_bytes(), member_wallet.key().as_ref()],
+        bump,
+        constraint = ban_record.data_is_empty() @ ErrorCode::MemberBanned
+    )]
+    pub ban_record: UncheckedAccount<'info>,
+
+    /// Token account holding the wallet's collection NFT (required for collection-gated channels)
+    pub nft_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// CHECK: Metaplex metadata of the NFT, verified in verify_collection_nft
+    pub nft_metadata: Option<UncheckedAccount<'info>>,
+
     /// User's token account (required for token-gated channels)
     #[account(mut)]
-    pub user_token_account: Option<Account<'info, TokenAccount>>,
+    pub user_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
 
     /// Token vault account (required for token-gated channels)
     #[account(
@@ -537,11 +3487,11 @@ pub struct JoinChannel<'info> {
 
     /// Vault's token account to receive staked tokens
     #[account(mut)]
-    pub vault_token_account: Option<Account<'info, TokenAccount>>,
+    pub vault_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
 
-    /// Member stake record (created for token-gated channels)
+    /// Member stake record (reused from the previous join, recreated if closed)
     #[account(
-        init,
+        init_if_needed,
         payer = member_wallet,
         space = 8 + MemberStake::LEN,
         seeds = [STAKE_SEED, channel.key().as_ref(), member_wallet.key().as_ref()],
@@ -549,208 +3499,519 @@ pub struct JoinChannel<'info> {
     )]
     pub member_stake: Option<Account<'info, MemberStake>>,
 
-    /// SPL Token program (required for token-gated channels)
-    pub token_program: Option<Program<'info, Token>>,
+    /// Gate mint, needed for transfer_checked (required for token-gated channels)
+    pub token_mint: Option<InterfaceAccount<'info, Mint>>,
+
+    /// Token program, SPL Token or Token-2022 (required for token-gated channels)
+    pub token_program: Option<Interface<'info, TokenInterface>>,
 
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct LogMessage<'info> {
+pub struct ManageChannelKey<'info> {
     #[account(mut)]
     pub channel: Account<'info, Channel>,
 
+    /// Signer's member record (not needed when the signer is the channel owner)
     #[account(
-        constraint = member.channel == channel.key() @ ErrorCode::NotChannelMember,
-        constraint = member.wallet == sender.key() @ ErrorCode::UnauthorizedSender
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
     )]
-    pub member: Account<'info, Member>,
+    pub authority_member: Option<Account<'info, Member>>,
 
-    pub sender: Signer<'info>,
+    /// Pays rent for the new envelopes
+    #[account(
+        mut,
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Admin)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct UpdateChannel<'info> {
+pub struct CloseKeyEnvelope<'info> {
+    #[account(mut, close = payer)]
+    pub key_envelope: Account<'info, KeyEnvelope>,
+
+    /// CHECK: Channel the envelope was written for, empty once it has been closed
+    #[account(address = key_envelope.channel)]
+    pub channel: UncheckedAccount<'info>,
+
+    /// CHECK: Rent refund destination, must be whoever uploaded the envelope
+    #[account(mut, address = key_envelope.payer)]
+    pub payer: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct PublishUserKey<'info> {
+    #[account(
+        init,
+        payer = wallet,
+        space = 8 + UserKey::LEN,
+        seeds = [USER_KEY_SEED, wallet.key().as_ref()],
+        bump
+    )]
+    pub user_key: Account<'info, UserKey>,
+
+    #[account(mut)]
+    pub wallet: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateUserKey<'info> {
     #[account(
         mut,
-        constraint = channel.owner == owner.key() @ ErrorCode::NotChannelOwner
+        seeds = [USER_KEY_SEED, wallet.key().as_ref()],
+        bump = user_key.bump
     )]
+    pub user_key: Account<'info, UserKey>,
+
+    pub wallet: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(required_token_mint: Pubkey)]
+pub struct SetTokenGate<'info> {
+    #[account(mut)]
     pub channel: Account<'info, Channel>,
 
-    pub owner: Signer<'info>,
+    /// Gate mint, owned by SPL Token or Token-2022
+    #[account(address = required_token_mint @ ErrorCode::TokenMintMismatch)]
+    pub token_mint: InterfaceAccount<'info, Mint>,
+
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
+
+    #[account(
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Admin)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct LeaveChannel<'info> {
+pub struct RevalidateMember<'info> {
     #[account(mut)]
     pub channel: Account<'info, Channel>,
 
     #[account(
         mut,
-        constraint = member.wallet == member_wallet.key() @ ErrorCode::UnauthorizedSender
+        seeds = [MEMBER_SEED, channel.key().as_ref(), member.wallet.as_ref()],
+        bump = member.bump
     )]
     pub member: Account<'info, Member>,
 
-    pub member_wallet: Signer<'info>,
+    /// CHECK: The token account the member joined with; may since have been closed
+    #[account(address = member.gate_token_account @ ErrorCode::TokenAccountOwnerMismatch)]
+    pub member_token_account: UncheckedAccount<'info>,
+}
 
-    /// Token vault account (for returning staked tokens)
+#[derive(Accounts)]
+pub struct SetCollectionGate<'info> {
     #[account(mut)]
-    pub token_vault: Option<Account<'info, TokenVault>>,
+    pub channel: Account<'info, Channel>,
 
-    /// CHECK: PDA authority for vault token transfers
-    pub vault_authority: Option<UncheckedAccount<'info>>,
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
 
-    /// Vault's token account (source of returned tokens)
-    #[account(mut)]
-    pub vault_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Admin)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
+}
 
-    /// User's token account (destination for returned tokens)
+#[derive(Accounts)]
+pub struct SetStakePolicy<'info> {
     #[account(mut)]
-    pub user_token_account: Option<Account<'info, TokenAccount>>,
+    pub channel: Account<'info, Channel>,
 
-    /// Member stake record
+    /// Signer's member record (not needed when the signer is the channel owner)
     #[account(
-        mut,
-        seeds = [STAKE_SEED, channel.key().as_ref(), member_wallet.key().as_ref()],
-        bump = member_stake.bump
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
     )]
-    pub member_stake: Option<Account<'info, MemberStake>>,
+    pub authority_member: Option<Account<'info, Member>>,
 
-    /// SPL Token program
-    pub token_program: Option<Program<'info, Token>>,
+    #[account(
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Admin)
+            @ ErrorCode::InsufficientRole
+    )]
+    pub authority: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct RejoinChannel<'info> {
-    #[account(mut)]
+pub struct InitializeVault<'info> {
+    #[account(
+        constraint = channel.required_token_mint.is_some() @ ErrorCode::NotTokenGatedChannel
+    )]
     pub channel: Account<'info, Channel>,
 
     #[account(
-        mut,
-        seeds = [MEMBER_SEED, channel.key().as_ref(), member_wallet.key().as_ref()],
-        bump = member.bump,
-        constraint = member.wallet == member_wallet.key() @ ErrorCode::UnauthorizedSender
+        init,
+        payer = authority,
+        space = 8 + TokenVault::LEN,
+        seeds = [VAULT_SEED, channel.key().as_ref()],
+        bump
     )]
-    pub member: Account<'info, Member>,
+    pub token_vault: Account<'info, TokenVault>,
 
-    #[account(mut)]
-    pub member_wallet: Signer<'info>,
+    /// CHECK: PDA authority for vault token account
+    #[account(
+        seeds = [VAULT_AUTH_SEED, channel.key().as_ref(), channel.required_token_mint.unwrap().as_ref()],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
 
-    /// User's token account (required for token-gated channels)
-    #[account(mut)]
-    pub user_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(
+        init,
+        payer = authority,
+        associated_token::mint = token_mint,
+        associated_token::authority = vault_authority,
+        associated_token::token_program = token_program,
+    )]
+    pub vault_token_account: InterfaceAccount<'info, TokenAccount>,
 
-    /// Token vault account (required for token-gated channels)
     #[account(
-        mut,
-        seeds = [VAULT_SEED, channel.key().as_ref()],
-        bump = token_vault.bump
+        address = channel.required_token_mint.unwrap() @ ErrorCode::TokenMintMismatch,
+        mint::token_program = token_program
     )]
-    pub token_vault: Option<Account<'info, TokenVault>>,
+    pub token_mint: InterfaceAccount<'info, Mint>,
 
-    /// Vault's token account to receive staked tokens
-    #[account(mut)]
-    pub vault_token_account: Option<Account<'info, TokenAccount>>,
+    /// Signer's member record (not needed when the signer is the channel owner)
+    #[account(
+        seeds = [MEMBER_SEED, channel.key().as_ref(), authority.key().as_ref()],
+        bump = authority_member.bump
+    )]
+    pub authority_member: Option<Account<'info, Member>>,
 
-    /// Member stake record (already exists from previous join)
     #[account(
         mut,
-        seeds = [STAKE_SEED, channel.key().as_ref(), member_wallet.key().as_ref()],
-        bump = member_stake.bump
+        constraint = channel.has_role(&authority.key(), authority_member.as_deref(), MemberRole::Admin)
+            @ ErrorCode::InsufficientRole
     )]
-    pub member_stake: Option<Account<'info, MemberStake>>,
-
-    /// SPL Token program (required for token-gated channels)
-    pub token_program: Option<Program<'info, Token>>,
+    pub authority: Signer<'info>,
 
+    /// SPL Token or Token-2022, whichever owns the mint
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct SetTokenGate<'info> {
+pub struct FundRewards<'info> {
     #[account(
-        mut,
         constraint = channel.owner == owner.key() @ ErrorCode::NotChannelOwner
     )]
     pub channel: Account<'info, Channel>,
 
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, channel.key().as_ref()],
+        bump = token_vault.bump
+    )]
+    pub token_vault: Account<'info, TokenVault>,
+
+    /// Vault's token account (destination for rewards)
+    #[account(mut)]
+    pub vault_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Owner's token account (source of rewards)
+    #[account(mut)]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Gate mint, needed for transfer_checked
+    pub token_mint: InterfaceAccount<'info, Mint>,
+
+    /// Token program, SPL Token or Token-2022
+    pub token_program: Interface<'info, TokenInterface>,
+
     pub owner: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct InitializeVault<'info> {
+pub struct ClaimRewards<'info> {
     #[account(
-        constraint = channel.required_token_mint.is_some() @ ErrorCode::NotTokenGatedChannel,
-        constraint = channel.owner == owner.key() @ ErrorCode::NotChannelOwner
+        mut,
+        seeds = [STAKE_SEED, member_stake.channel.as_ref(), wallet.key().as_ref()],
+        bump = member_stake.bump
     )]
-    pub channel: Account<'info, Channel>,
+    pub member_stake: Account<'info, MemberStake>,
 
     #[account(
-        init,
-        payer = owner,
-        space = 8 + TokenVault::LEN,
-        seeds = [VAULT_SEED, channel.key().as_ref()],
-        bump
+        mut,
+        seeds = [VAULT_SEED, member_stake.channel.as_ref()],
+        bump = token_vault.bump
     )]
     pub token_vault: Account<'info, TokenVault>,
 
-    /// CHECK: PDA authority for vault token account
-    #[account(
-        seeds = [VAULT_AUTH_SEED, channel.key().as_ref(), channel.required_token_mint.unwrap().as_ref()],
-        bump
-    )]
-    pub vault_authority: UncheckedAccount<'info>,
+    /// CHECK: PDA authority for vault token transfers
+    pub vault_authority: UncheckedAccount<'info>,
+
+    /// Vault's token account (source of rewards)
+    #[account(mut)]
+    pub vault_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// User's token account (destination for rewards)
+    #[account(mut)]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Gate mint, needed for transfer_checked
+    pub token_mint: InterfaceAccount<'info, Mint>,
+
+    /// Token program, SPL Token or Token-2022
+    pub token_program: Interface<'info, TokenInterface>,
+
+    pub wallet: Signer<'info>,
+}
+
+// ==================== STATE ====================
+
+#[account]
+pub struct Channel {
+    pub channel_id: u64,                    // 8
+    pub owner: Pubkey,                      // 32
+    pub encrypted_metadata: Vec<u8>,        // 4 + metadata length (up to MAX_METADATA_SIZE)
+    pub channel_type: ChannelType,          // 1
+    pub member_count: u16,                  // 2
+    pub message_count: u64,                 // 8
+    pub created_at: i64,                    // 8 (also seeds invites, bans, message records, envelopes, pins)
+    pub is_active: bool,                    // 1
+    pub required_token_mint: Option<Pubkey>, // 33 (1 + 32)
+    pub min_token_amount: Option<u64>,      // 9 (1 + 8)
+    pub bump: u8,                           // 1
+    // Fields below were added after launch and read as zero on older channels
+    pub pending_owner: Option<Pubkey>,      // 33 (1 + 32)
+    pub max_members: u16,                   // 2
+    pub key_epoch: u32,                     // 4
+    pub message_digest: [u8; 32],           // 32 (running hash over message history)
+    pub slow_mode_seconds: u32,             // 4 (0 = no rate limit)
+    pub posting_policy: PostingPolicy,      // 2 (1 + MemberRole)
+    pub retention_seconds: u32,             // 4 (0 = keep forever)
+    pub expired_through: u64,               // 8 (last message covered by MessagesExpired)
+    pub retention_mark: u64,                // 8 (message count when the mark was taken)
+    pub retention_mark_at: i64,             // 8 (0 = no mark pending)
+    pub required_collection: Option<Pubkey>, // 33 (1 + 32, verified Metaplex collection)
+    pub gate_mode: GateMode,                // 1
+    pub min_lock_seconds: u32,              // 4 (0 = stake can leave at any time)
+    pub early_exit_penalty_bps: u16,        // 2 (share of stake kept when leaving early)
+    pub treasury: Option<Pubkey>,           // 33 (1 + 32, token account receiving penalties)
+}
+
+impl Channel {
+    /// Size of every field except the metadata bytes themselves
+    pub const BASE_LEN: usize = 8 + 32 + 4 + 1 + 2 + 8 + 8 + 1 + 33 + 9 + 1 + 33 + 2 + 4 + 32 + 4 + 2 + 4 + 8 + 8 + 8 + 33 + 1 + 4 + 2 + 33;
+
+    /// Offset of the metadata length prefix (discriminator + channel_id + owner)
+    const METADATA_LEN_OFFSET: usize = 8 + 8 + 32;
+
+    /// Account size (including discriminator) for the given metadata length
+    pub fn space(metadata_len: usize) -> usize {
+        8 + Self::BASE_LEN + metadata_len
+    }
+
+    /// Metadata length read straight from raw account data, usable before
+    /// the account is known to fit the current layout
+    pub fn stored_metadata_len(data: &[u8]) -> Result<usize> {
+        let prefix = data
+            .get(Self::METADATA_LEN_OFFSET..Self::METADATA_LEN_OFFSET + 4)
+            .ok_or(anchor_lang::error::ErrorCode::AccountDidNotDeserialize)?;
+        Ok(u32::from_le_bytes(prefix.try_into().unwrap()) as usize)
+    }
+
+    /// Fold one message into the running history digest
+    /// digest_n = blake3(digest_n-1 || message_number || sender || message_hash || timestamp),
+    /// starting from all zeros, so a client can replay events and detect gaps
+    pub fn next_digest(
+        previous: &[u8; 32],
+        message_number: u64,
+        sender: &Pubkey,
+        message_hash: &[u8; 32],
+        timestamp: i64,
+    ) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(previous);
+        hasher.update(&message_number.to_le_bytes());
+        hasher.update(sender.as_ref());
+        hasher.update(message_hash);
+        hasher.update(&timestamp.to_le_bytes());
+        *hasher.finalize().as_bytes()
+    }
+
+    /// Whether a member may post under the channel's posting policy
+    /// The owner can always post
+    pub fn can_post(&self, member: &Member) -> bool {
+        match self.posting_policy {
+            PostingPolicy::Everyone => true,
+            PostingPolicy::MinimumRole(role) => self.has_role(&member.wallet, Some(member), role),
+            PostingPolicy::Allowlist => member.can_post || member.wallet == self.owner,
+        }
+    }
+
+    /// Channels that predate max_members (not yet migrated) keep the old default
+    pub fn member_limit(&self) -> u16 {
+        if self.max_members == 0 {
+            DEFAULT_MAX_MEMBERS
+        } else {
+            self.max_members
+        }
+    }
+
+    /// Effective role of a wallet in this channel
+    /// The owner key always counts as Owner, even without a Member account
+    pub fn role_of(&self, wallet: &Pubkey, member: Option<&Member>) -> Option<MemberRole> {
+        if *wallet == self.owner {
+            return Some(MemberRole::Owner);
+        }
+
+        member
+            .filter(|m| m.is_active && m.wallet == *wallet)
+            .map(|m| m.role)
+    }
+
+    pub fn has_role(&self, wallet: &Pubkey, member: Option<&Member>, min_role: MemberRole) -> bool {
+        self.role_of(wallet, member).is_some_and(|role| role >= min_role)
+    }
+}
+
+#[account]
+pub struct Member {
+    pub channel: Pubkey,        // 32
+    pub wallet: Pubkey,         // 32
+    pub joined_at: i64,         // 8
+    pub is_active: bool,        // 1
+    pub bump: u8,               // 1
+    // Fields below were added after launch and read as zero on older members
+    pub role: MemberRole,       // 1
+    pub key_version: u32,       // 4 (UserKey version at join time, 0 if none)
+    pub last_message_at: i64,   // 8
+    pub can_post: bool,         // 1 (poster allowlist, see PostingPolicy::Allowlist)
+    pub gate_token_account: Pubkey, // 32 (token account used to pass the gate, default if none)
+}
+
+impl Member {
+    pub const LEN: usize = 32 + 32 + 8 + 1 + 1 + 1 + 4 + 8 + 1 + 32;
+}
+
+#[account]
+pub struct Invite {
+    pub channel: Pubkey,           // 32
+    pub creator: Pubkey,           // 32
+    pub invite_key: Pubkey,        // 32 (public key of the invite keypair)
+    pub expires_at: Option<i64>,   // 9 (1 + 8)
+    pub max_uses: Option<u32>,     // 5 (1 + 4)
+    pub uses: u32,                 // 4
+    pub created_at: i64,           // 8
+    pub bump: u8,                  // 1
+}
+
+impl Invite {
+    pub const LEN: usize = 32 + 32 + 32 + 9 + 5 + 4 + 8 + 1;
+}
 
-    #[account(
-        init,
-        payer = owner,
-        associated_token::mint = token_mint,
-        associated_token::authority = vault_authority,
-    )]
-    pub vault_token_account: Account<'info, TokenAccount>,
+#[account]
+pub struct Ban {
+    pub channel: Pubkey,           // 32
+    pub wallet: Pubkey,            // 32
+    pub banned_by: Pubkey,         // 32
+    pub banned_at: i64,            // 8
+    pub bump: u8,                  // 1
+}
 
-    pub token_mint: Account<'info, Mint>,
+impl Ban {
+    pub const LEN: usize = 32 + 32 + 32 + 8 + 1;
+}
 
-    #[account(mut)]
-    pub owner: Signer<'info>,
+#[account]
+pub struct UserKey {
+    pub wallet: Pubkey,            // 32
+    pub encryption_key: [u8; 32],  // 32 (X25519 public key)
+    pub version: u32,              // 4
+    pub updated_at: i64,           // 8
+    pub is_revoked: bool,          // 1
+    pub bump: u8,                  // 1
+}
 
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
+impl UserKey {
+    pub const LEN: usize = 32 + 32 + 4 + 8 + 1 + 1;
+
+    /// Key version to record on a Member, 0 when no usable key is published
+    pub fn version_of(user_key: Option<&UserKey>) -> u32 {
+        user_key
+            .filter(|key| !key.is_revoked)
+            .map_or(0, |key| key.version)
+    }
 }
 
-// ==================== STATE ====================
+/// Proof of who sent a message, kept so the sender can edit or delete it
+#[account]
+pub struct MessageRecord {
+    pub channel: Pubkey,           // 32
+    pub message_number: u64,       // 8
+    pub sender: Pubkey,            // 32
+    pub message_hash: [u8; 32],    // 32 (latest hash, updated on edit)
+    pub created_at: i64,           // 8
+    pub bump: u8,                  // 1
+}
+
+impl MessageRecord {
+    pub const LEN: usize = 32 + 8 + 32 + 32 + 8 + 1;
+}
 
+/// Message numbers pinned in a channel, shared by every client
 #[account]
-pub struct Channel {
-    pub channel_id: u64,                    // 8
-    pub owner: Pubkey,                      // 32
-    pub encrypted_metadata: Vec<u8>,        // 4 + MAX_METADATA_SIZE (512)
-    pub channel_type: ChannelType,          // 1
-    pub member_count: u16,                  // 2
-    pub message_count: u64,                 // 8
-    pub created_at: i64,                    // 8
-    pub is_active: bool,                    // 1
-    pub required_token_mint: Option<Pubkey>, // 33 (1 + 32)
-    pub min_token_amount: Option<u64>,      // 9 (1 + 8)
-    pub bump: u8,                           // 1
+pub struct ChannelPins {
+    pub channel: Pubkey,           // 32
+    pub messages: Vec<u64>,        // 4 + 8 * MAX_PINNED_MESSAGES
+    pub bump: u8,                  // 1
 }
 
-impl Channel {
-    pub const LEN: usize = 8 + 32 + (4 + MAX_METADATA_SIZE) + 1 + 2 + 8 + 8 + 1 + 33 + 9 + 1;
+impl ChannelPins {
+    pub const LEN: usize = 32 + 4 + 8 * MAX_PINNED_MESSAGES + 1;
 }
 
+/// A channel key for one epoch, wrapped to a member's published UserKey
 #[account]
-pub struct Member {
-    pub channel: Pubkey,        // 32
-    pub wallet: Pubkey,         // 32
-    pub joined_at: i64,         // 8
-    pub is_active: bool,        // 1
-    pub bump: u8,               // 1
+pub struct KeyEnvelope {
+    pub channel: Pubkey,           // 32
+    pub wallet: Pubkey,            // 32
+    pub key_epoch: u32,            // 4
+    pub key_version: u32,          // 4 (UserKey version the key is wrapped to)
+    pub wrapped_key: Vec<u8>,      // 4 + MAX_WRAPPED_KEY_SIZE
+    pub created_at: i64,           // 8
+    pub bump: u8,                  // 1
+    pub payer: Pubkey,             // 32 (receives the rent on close_key_envelope)
 }
 
-impl Member {
-    pub const LEN: usize = 32 + 32 + 8 + 1 + 1;
+impl KeyEnvelope {
+    pub const LEN: usize = 32 + 32 + 4 + 4 + 4 + MAX_WRAPPED_KEY_SIZE + 8 + 1 + 32;
+}
+
+/// Instruction input for one message in a log_messages batch
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct MessageEntry {
+    pub message_hash: [u8; 32],
+    pub encrypted_ipfs_cid: Vec<u8>,
+}
+
+/// Instruction input for one member's envelope
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct WrappedKey {
+    pub key_version: u32,
+    pub wrapped_key: Vec<u8>,
 }
 
 #[account]
@@ -760,23 +4021,144 @@ pub struct TokenVault {
     pub total_locked: u64,         // 8
     pub bump: u8,                  // 1
     pub auth_bump: u8,             // 1
+    // Fields below were added after launch and read as zero on older vaults
+    pub reward_per_share: u128,    // 16 (rewards per locked token, scaled by REWARD_PRECISION)
+    pub reward_balance: u64,       // 8 (funded rewards not yet paid out)
+    pub reward_unreleased: u64,    // 8 (funded rewards still streaming in)
+    pub reward_end: i64,           // 8 (time the current stream finishes)
+    pub last_reward_update: i64,   // 8
+    pub rewards_owed: u64,         // 8 (settled into stakes but not yet paid out)
 }
 
 impl TokenVault {
-    pub const LEN: usize = 32 + 32 + 8 + 1 + 1;
+    pub const LEN: usize = 32 + 32 + 8 + 1 + 1 + 16 + 8 + 8 + 8 + 8 + 8;
+
+    /// Vault authority PDA that owns the vault token account
+    pub fn authority(&self) -> Result<Pubkey> {
+        Pubkey::create_program_address(
+            &[
+                VAULT_AUTH_SEED,
+                self.channel.as_ref(),
+                self.token_mint.as_ref(),
+                &[self.auth_bump],
+            ],
+            &crate::ID,
+        )
+        .map_err(|_| error!(ErrorCode::InvalidVaultAccount))
+    }
+
+    /// Release the streamed rewards due since the last update into reward_per_share
+    /// Must run before every change to total_locked. While nothing is locked the
+    /// stream pauses, so the rest is released over the remaining duration later
+    pub fn accrue_rewards(&mut self, now: i64) -> Result<()> {
+        if now <= self.last_reward_update {
+            return Ok(());
+        }
+
+        if self.reward_unreleased > 0 {
+            if self.total_locked == 0 {
+                self.reward_end = self.reward_end.saturating_add(now - self.last_reward_update);
+            } else {
+                let released = if now >= self.reward_end {
+                    self.reward_unreleased
+                } else {
+                    // last_reward_update < now < reward_end, so this is a fraction
+                    // of what is left
+                    (self.reward_unreleased as u128 * (now - self.last_reward_update) as u128
+                        / (self.reward_end - self.last_reward_update) as u128) as u64
+                };
+                let increase = (released as u128)
+                    .checked_mul(REWARD_PRECISION)
+                    .ok_or(ErrorCode::Overflow)?
+                    / self.total_locked as u128;
+                self.reward_per_share = self.reward_per_share
+                    .checked_add(increase)
+                    .ok_or(ErrorCode::Overflow)?;
+                self.reward_unreleased -= released;
+            }
+        }
+        self.last_reward_update = now;
+
+        Ok(())
+    }
+
+    /// Add newly funded rewards to the stream, which then runs for `duration`
+    /// seconds from now together with anything not yet released
+    pub fn stream_rewards(&mut self, amount: u64, duration: u32, now: i64) -> Result<()> {
+        require!(self.total_locked > 0, ErrorCode::NoStakers);
+
+        self.accrue_rewards(now)?;
+        self.reward_unreleased = self.reward_unreleased
+            .checked_add(amount)
+            .ok_or(ErrorCode::Overflow)?;
+        self.reward_balance = self.reward_balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::Overflow)?;
+        self.reward_end = now.saturating_add(duration as i64);
+        self.last_reward_update = now;
+
+        Ok(())
+    }
 }
 
 #[account]
 pub struct MemberStake {
-    pub member: Pubkey,            // 32
-    pub channel: Pubkey,           // 32
-    pub locked_amount: u64,        // 8
-    pub lock_timestamp: i64,       // 8
-    pub bump: u8,                  // 1
+    pub member: Pubkey,                // 32
+    pub channel: Pubkey,               // 32
+    pub locked_amount: u64,            // 8
+    pub lock_timestamp: i64,           // 8
+    pub bump: u8,                      // 1
+    // Fields below were added after launch and read as zero on older stakes
+    pub reward_checkpoint: u128,       // 16 (vault reward_per_share at last settlement)
+    pub pending_rewards: u64,          // 8
+    pub lock_ends_at: i64,             // 8 (lock period fixed when the stake was made)
+    pub early_exit_penalty_bps: u16,   // 2 (penalty fixed when the stake was made)
 }
 
 impl MemberStake {
-    pub const LEN: usize = 32 + 32 + 8 + 8 + 1;
+    pub const LEN: usize = 32 + 32 + 8 + 8 + 1 + 16 + 8 + 8 + 2;
+
+    /// Lock `amount` under the channel's current stake policy
+    /// Later set_stake_policy calls do not change the terms of this stake
+    pub fn lock(&mut self, channel: &Channel, amount: u64, now: i64) {
+        self.locked_amount = amount;
+        self.lock_timestamp = now;
+        self.lock_ends_at = now.saturating_add(channel.min_lock_seconds as i64);
+        self.early_exit_penalty_bps = channel.early_exit_penalty_bps;
+    }
+
+    /// Move rewards accrued since the last checkpoint into pending_rewards
+    /// Must run before every change to locked_amount
+    pub fn settle_rewards(&mut self, vault: &mut TokenVault) -> Result<()> {
+        vault.accrue_rewards(Clock::get()?.unix_timestamp)?;
+
+        let growth = vault.reward_per_share.saturating_sub(self.reward_checkpoint);
+        let accrued = (self.locked_amount as u128)
+            .checked_mul(growth)
+            .ok_or(ErrorCode::Overflow)?
+            / REWARD_PRECISION;
+        let accrued = u64::try_from(accrued).map_err(|_| error!(ErrorCode::Overflow))?;
+
+        self.pending_rewards = self.pending_rewards
+            .checked_add(accrued)
+            .ok_or(ErrorCode::Overflow)?;
+        self.reward_checkpoint = vault.reward_per_share;
+        vault.rewards_owed = vault.rewards_owed
+            .checked_add(accrued)
+            .ok_or(ErrorCode::Overflow)?;
+
+        Ok(())
+    }
+
+    /// Tokens forfeited when leaving at `now` (0 once the lock has passed)
+    pub fn early_exit_penalty(&self, now: i64) -> u64 {
+        if now >= self.lock_ends_at {
+            return 0;
+        }
+        // bps <= MAX_PENALTY_BPS, so the result never exceeds locked_amount
+        (self.locked_amount as u128 * self.early_exit_penalty_bps as u128
+            / MAX_PENALTY_BPS as u128) as u64
+    }
 }
 
 // ==================== ENUMS ====================
@@ -789,6 +4171,30 @@ pub enum ChannelType {
     Public,             // Anyone can join
 }
 
+/// How a token gate is satisfied
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GateMode {
+    Stake,              // min_token_amount is escrowed in the vault until leave
+    Hold,               // Balance is checked on join and by revalidate_member
+}
+
+/// Who may log messages in a channel
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PostingPolicy {
+    Everyone,                   // Any active member
+    MinimumRole(MemberRole),    // Members at or above a role, e.g. announcements
+    Allowlist,                  // Members flagged with set_member_posting
+}
+
+/// Ordered lowest to highest, so roles compare with < and >
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum MemberRole {
+    Member,
+    Moderator,
+    Admin,
+    Owner,
+}
+
 // ==================== EVENTS ====================
 
 #[event]
@@ -798,6 +4204,120 @@ pub struct MessageLogged {
     pub message_hash: [u8; 32],
     pub encrypted_ipfs_cid: Vec<u8>,
     pub message_number: u64,
+    pub reply_to: Option<u64>,
+    pub thread_root: Option<u64>,
+    pub key_epoch: u32,
+    pub digest: [u8; 32],
+    pub retention_seconds: u32,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct MessagesExpired {
+    pub channel: Pubkey,
+    pub up_to_message: u64,
+    pub retention_seconds: u32,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct StakePenalized {
+    pub channel: Pubkey,
+    pub member: Pubkey,
+    pub penalty: u64,
+    pub returned: u64,
+    pub treasury: Option<Pubkey>,
+    pub lock_ends_at: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct StakeSlashed {
+    pub channel: Pubkey,
+    pub member: Pubkey,
+    pub amount: u64,
+    pub remaining: u64,
+    pub reason: u8,
+    pub deactivated: bool,
+    pub slashed_by: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RewardsFunded {
+    pub channel: Pubkey,
+    pub amount: u64,
+    pub total_locked: u64,
+    pub release_ends_at: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RewardsClaimed {
+    pub channel: Pubkey,
+    pub member: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct MessageEdited {
+    pub channel: Pubkey,
+    pub sender: Pubkey,
+    pub message_number: u64,
+    pub new_message_hash: [u8; 32],
+    pub new_encrypted_ipfs_cid: Vec<u8>,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct MessageDeleted {
+    pub channel: Pubkey,
+    pub sender: Pubkey,
+    pub message_number: u64,
+    pub deleted_by: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ReactionAdded {
+    pub channel: Pubkey,
+    pub reactor: Pubkey,
+    pub message_number: u64,
+    pub reaction: Vec<u8>,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ReactionRemoved {
+    pub channel: Pubkey,
+    pub reactor: Pubkey,
+    pub message_number: u64,
+    pub reaction: Vec<u8>,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct MessagePinned {
+    pub channel: Pubkey,
+    pub message_number: u64,
+    pub pinned_by: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct MessageUnpinned {
+    pub channel: Pubkey,
+    pub message_number: u64,
+    pub unpinned_by: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ChannelKeyRotated {
+    pub channel: Pubkey,
+    pub key_epoch: u32,
+    pub rotated_by: Pubkey,
     pub timestamp: i64,
 }
 
@@ -846,4 +4366,335 @@ pub enum ErrorCode {
 
     #[msg("Arithmetic overflow")]
     Overflow,
+
+    #[msg("This channel is invite-only")]
+    InviteRequired,
+
+    #[msg("Transaction is not signed by the invite key")]
+    InvalidInviteSigner,
+
+    #[msg("Invite has expired")]
+    InviteExpired,
+
+    #[msg("Invite has no remaining uses")]
+    InviteExhausted,
+
+    #[msg("Invite expiry must be in the future")]
+    InvalidInviteExpiry,
+
+    #[msg("Invite max uses must be greater than zero")]
+    InvalidInviteMaxUses,
+
+    #[msg("Signer's role is too low for this action")]
+    InsufficientRole,
+
+    #[msg("Invalid role change")]
+    InvalidRoleChange,
+
+    #[msg("Wallet is banned from this channel")]
+    MemberBanned,
+
+    #[msg("Signer is not the pending channel owner")]
+    NotPendingOwner,
+
+    #[msg("Owner must name a successor before leaving a non-empty channel")]
+    SuccessorRequired,
+
+    #[msg("Successor must be an active admin of this channel")]
+    InvalidSuccessor,
+
+    #[msg("Channel still has members")]
+    ChannelNotEmpty,
+
+    #[msg("Token vault still holds staked tokens")]
+    VaultNotEmpty,
+
+    #[msg("Vault account does not belong to this channel's vault")]
+    InvalidVaultAccount,
+
+    #[msg("Member must leave the channel first")]
+    MemberStillActive,
+
+    #[msg("Stake record must be closed first")]
+    StakeNotClosed,
+
+    #[msg("Previous stake must be recovered with close_stake first")]
+    StakeStillLocked,
+
+    #[msg("Direct messages must be created with create_direct_message")]
+    UseCreateDirectMessage,
+
+    #[msg("Cannot open a direct message with yourself")]
+    InvalidDirectMessagePeer,
+
+    #[msg("Direct messages are limited to their two participants")]
+    DirectMessageFull,
+
+    #[msg("Member limit must cover current members and stay within the program maximum")]
+    InvalidMemberLimit,
+
+    #[msg("User key is revoked")]
+    UserKeyRevoked,
+
+    #[msg("Key envelope accounts do not match the wrapped keys")]
+    InvalidKeyEnvelope,
+
+    #[msg("Wrapped key is empty or exceeds maximum size")]
+    WrappedKeyTooLarge,
+
+    #[msg("Reply target must be an existing message in this channel")]
+    InvalidReplyTarget,
+
+    #[msg("Message batch must contain between 1 and MAX_BATCH_MESSAGES entries")]
+    InvalidBatchSize,
+
+    #[msg("Reaction is empty or exceeds maximum size")]
+    InvalidReaction,
+
+    #[msg("Message number does not refer to a logged message")]
+    InvalidMessageNumber,
+
+    #[msg("Message is already pinned")]
+    AlreadyPinned,
+
+    #[msg("Message is not pinned")]
+    NotPinned,
+
+    #[msg("Channel has reached maximum pinned messages")]
+    PinLimitReached,
+
+    #[msg("Slow mode is on, wait before posting again")]
+    SlowModeActive,
+
+    #[msg("Channel posting policy does not allow this member to post")]
+    PostingNotAllowed,
+
+    #[msg("Channel has no retention period")]
+    RetentionDisabled,
+
+    #[msg("Messages up to this number have not passed the retention period")]
+    InvalidExpiryCheckpoint,
+
+    #[msg("An NFT from the required collection is needed to join")]
+    CollectionNftRequired,
+
+    #[msg("NFT metadata account is invalid")]
+    InvalidNftMetadata,
+
+    #[msg("NFT is not a verified member of the required collection")]
+    CollectionMismatch,
+
+    #[msg("Channel does not use a hold-to-join token gate")]
+    NotHoldGatedChannel,
+
+    #[msg("Member still meets the token gate")]
+    MemberStillEligible,
+
+    #[msg("Penalty cannot exceed 10000 basis points")]
+    InvalidPenalty,
+
+    #[msg("Treasury token account is required")]
+    TreasuryRequired,
+
+    #[msg("Token account is not the channel treasury")]
+    InvalidTreasury,
+
+    #[msg("Stake is still within its lock period")]
+    StakeLocked,
+
+    #[msg("Channel account is required")]
+    ChannelAccountRequired,
+
+    #[msg("Slash amount must be between 1 and the locked stake")]
+    InvalidSlashAmount,
+
+    #[msg("Reward amount must be greater than zero")]
+    InvalidRewardAmount,
+
+    #[msg("Vault has no locked stake to distribute rewards to")]
+    NoStakers,
+
+    #[msg("No rewards to claim")]
+    NoRewardsToClaim,
+
+    #[msg("Channel must be migrated first")]
+    ChannelNotMigrated,
+
+    #[msg("Reward duration must be greater than zero")]
+    InvalidRewardDuration,
+
+    #[msg("Stakers still have unclaimed rewards")]
+    RewardsUnclaimed,
+
+    #[msg("Stake account is not owned by this program")]
+    InvalidStakeAccount,
+
+    #[msg("Message has a record, retract it with delete_message")]
+    MessageRecordExists,
+
+    #[msg("Key envelope is still for the channel's current key epoch")]
+    KeyEnvelopeInUse,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use borsh010::BorshSerialize;
+    use mpl_token_metadata::accounts::Metadata;
+    use mpl_token_metadata::types::{Collection, Creator, Key, TokenStandard};
+
+    /// Size the metadata program allocates for every MetadataV1 account
+    const METADATA_ACCOUNT_LEN: usize = 679;
+
+    /// Account data as the metadata program writes it: name, symbol and uri
+    /// padded to their maximum lengths and the account zero-filled to full size
+    fn metadata_bytes(key: Key, collection: Option<Collection>) -> Vec<u8> {
+        let pad = |value: &str, len: usize| format!("{value:\0<len$}");
+        let metadata = Metadata {
+            key,
+            update_authority: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            name: pad("Shield Pass #1", 32),
+            symbol: pad("SHLD", 10),
+            uri: pad("https://example.com/1.json", 200),
+            seller_fee_basis_points: 500,
+            creators: Some(vec![Creator {
+                address: Pubkey::new_unique(),
+                verified: true,
+                share: 100,
+            }]),
+            primary_sale_happened: true,
+            is_mutable: true,
+            edition_nonce: Some(254),
+            token_standard: Some(TokenStandard::NonFungible),
+            collection,
+            uses: None,
+            collection_details: None,
+            programmable_config: None,
+        };
+
+        let mut data = metadata.try_to_vec().unwrap();
+        assert!(data.len() <= METADATA_ACCOUNT_LEN);
+        data.resize(METADATA_ACCOUNT_LEN, 0);
+        data
+    }
+
+    #[test]
+    fn metadata_collection_reads_verified_collection() {
+        let collection = Pubkey::new_unique();
+        let data = metadata_bytes(
+            Key::MetadataV1,
+            Some(Collection { verified: true, key: collection }),
+        );
+
+        assert_eq!(metadata_collection(&data), Some(Some((true, collection))));
+    }
+
+    #[test]
+    fn metadata_collection_reads_unverified_collection() {
+        let collection = Pubkey::new_unique();
+        let data = metadata_bytes(
+            Key::MetadataV1,
+            Some(Collection { verified: false, key: collection }),
+        );
+
+        assert_eq!(metadata_collection(&data), Some(Some((false, collection))));
+    }
+
+    #[test]
+    fn metadata_collection_reports_the_actual_collection() {
+        let required = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let data = metadata_bytes(Key::MetadataV1, Some(Collection { verified: true, key: other }));
+
+        let collection = metadata_collection(&data).unwrap();
+        assert_eq!(collection, Some((true, other)));
+        assert_ne!(collection, Some((true, required)));
+    }
+
+    #[test]
+    fn metadata_collection_handles_missing_collection() {
+        let data = metadata_bytes(Key::MetadataV1, None);
+
+        assert_eq!(metadata_collection(&data), Some(None));
+    }
+
+    #[test]
+    fn metadata_collection_rejects_other_account_kinds() {
+        let data = metadata_bytes(
+            Key::MasterEditionV2,
+            Some(Collection { verified: true, key: Pubkey::new_unique() }),
+        );
+
+        assert_eq!(metadata_collection(&data), None);
+    }
+
+    #[test]
+    fn metadata_collection_rejects_truncated_data() {
+        let data = metadata_bytes(
+            Key::MetadataV1,
+            Some(Collection { verified: true, key: Pubkey::new_unique() }),
+        );
+
+        assert_eq!(metadata_collection(&data[..100]), None);
+        assert_eq!(metadata_collection(&[]), None);
+    }
+
+    fn staked_vault(total_locked: u64) -> TokenVault {
+        TokenVault {
+            channel: Pubkey::new_unique(),
+            token_mint: Pubkey::new_unique(),
+            total_locked,
+            bump: 255,
+            auth_bump: 255,
+            reward_per_share: 0,
+            reward_balance: 0,
+            reward_unreleased: 0,
+            reward_end: 0,
+            last_reward_update: 0,
+            rewards_owed: 0,
+        }
+    }
+
+    #[test]
+    fn stream_rewards_releases_linearly() {
+        let mut vault = staked_vault(100);
+        vault.stream_rewards(100, 100, 1_000).unwrap();
+        assert_eq!(vault.reward_per_share, 0);
+        assert_eq!(vault.reward_unreleased, 100);
+
+        vault.accrue_rewards(1_050).unwrap();
+        assert_eq!(vault.reward_per_share, REWARD_PRECISION / 2);
+        assert_eq!(vault.reward_unreleased, 50);
+
+        // Past the end everything left is released
+        vault.accrue_rewards(2_000).unwrap();
+        assert_eq!(vault.reward_per_share, REWARD_PRECISION);
+        assert_eq!(vault.reward_unreleased, 0);
+        assert_eq!(vault.reward_balance, 100);
+    }
+
+    #[test]
+    fn stream_rewards_pauses_while_nothing_is_locked() {
+        let mut vault = staked_vault(100);
+        vault.stream_rewards(100, 100, 0).unwrap();
+        vault.accrue_rewards(50).unwrap();
+
+        vault.total_locked = 0;
+        vault.accrue_rewards(80).unwrap();
+        assert_eq!(vault.reward_per_share, REWARD_PRECISION / 2);
+        assert_eq!(vault.reward_end, 130);
+
+        // The remaining 50 tokens still take the remaining 50 seconds
+        vault.total_locked = 100;
+        vault.accrue_rewards(105).unwrap();
+        assert_eq!(vault.reward_unreleased, 25);
+    }
+
+    #[test]
+    fn stream_rewards_requires_stakers() {
+        let mut vault = staked_vault(0);
+
+        assert!(vault.stream_rewards(100, 100, 0).is_err());
+    }
 }